@@ -0,0 +1,256 @@
+use std::{fmt::Display, io::{Read, Write}};
+
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{config::Padding, encryption::{decrypt_padded_payload, encrypt_padded_payload, encrypt_payload, signature::sign_packet}, packets::{protocol::PADDING, BlobChunkData, Packet, PacketGenerationError, PacketHeader, PacketReadingError}};
+
+/// Size of the plaintext of every chunk but the last one
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Size of the authentication tag appended to each encrypted chunk
+const TAG_SIZE: usize = 16;
+
+/// Everything the recipient needs to fetch and decrypt an attachment. It is only sent inside the
+/// encrypted content of a message, never in clear to the relay.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct AttachmentDescriptor {
+    pub blob_id: String,
+    pub key: String,
+    /// Url safe base64 of the SHA-256 of the encrypted blob
+    pub hash: String,
+    /// Size of the plaintext in bytes
+    pub size: u64,
+    pub mime_type: String,
+    pub name: String,
+}
+
+/// Plaintext of a message content, text along with its attachments
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct MessagePayload {
+    pub text: String,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentDescriptor>,
+}
+
+#[derive(Debug)]
+pub enum AttachmentError {
+    Io(std::io::Error),
+    InvalidKey,
+    Decryption,
+    Truncated,
+    Integrity,
+}
+
+impl Display for AttachmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttachmentError::Io(e) => {
+                write!(f, "IO error: {}", e)
+            }
+            AttachmentError::InvalidKey => {
+                write!(f, "Invalid attachment key")
+            }
+            AttachmentError::Decryption => {
+                write!(f, "Unable to decrypt attachment chunk")
+            }
+            AttachmentError::Truncated => {
+                write!(f, "Attachment is truncated")
+            }
+            AttachmentError::Integrity => {
+                write!(f, "Attachment hash does not match its descriptor")
+            }
+        }
+    }
+}
+impl std::error::Error for AttachmentError {}
+
+/// Nonce of a chunk: big endian chunk index followed by a flag set on the last chunk, so that
+/// chunks can't be reordered, dropped or the blob truncated without being noticed
+fn chunk_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::from(nonce)
+}
+
+fn attachment_cipher(key: &str) -> Result<ChaCha20Poly1305, AttachmentError> {
+    let key: [u8; 32] = URL_SAFE.decode(key).map_err(|_| AttachmentError::InvalidKey)?.try_into().map_err(|_| AttachmentError::InvalidKey)?;
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+/// Fill `buffer` as much as possible, only returning less than its size at the end of the stream
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// Encrypt an attachment chunk by chunk with a new random key, writing the encrypted blob to
+/// `writer`. The returned descriptor must be sent in the message content.
+pub fn encrypt_attachment(mut reader: impl Read, mut writer: impl Write, mime_type: &str, name: &str) -> Result<AttachmentDescriptor, AttachmentError> {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    let key = URL_SAFE.encode(key);
+    let cipher = attachment_cipher(&key)?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut size = 0u64;
+    let mut index = 0u64;
+
+    loop {
+        let read = read_full(&mut reader, &mut buffer)?;
+        // a full chunk is never the last one, an empty last chunk is written when needed
+        let last = read < CHUNK_SIZE;
+        let chunk = cipher.encrypt(&chunk_nonce(index, last), &buffer[..read]).map_err(|_| AttachmentError::InvalidKey)?;
+
+        hasher.update(&chunk);
+        writer.write_all(&chunk)?;
+        size += read as u64;
+        index += 1;
+
+        if last {
+            break;
+        }
+    }
+    writer.flush()?;
+
+    Ok(AttachmentDescriptor {
+        blob_id: Uuid::new_v4().to_string(),
+        key,
+        hash: URL_SAFE.encode(hasher.finalize()),
+        size,
+        mime_type: mime_type.to_string(),
+        name: name.to_string(),
+    })
+}
+
+/// Decrypt a blob produced by [`encrypt_attachment`]. Chunks are written as soon as they are
+/// authenticated, the hash and size are checked once the whole blob has been read: the output
+/// must be discarded if an error is returned.
+///
+/// Returns the number of plaintext bytes written.
+pub fn decrypt_attachment(mut reader: impl Read, mut writer: impl Write, descriptor: &AttachmentDescriptor) -> Result<u64, AttachmentError> {
+    let cipher = attachment_cipher(&descriptor.key)?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+    let mut size = 0u64;
+    let mut index = 0u64;
+
+    loop {
+        let read = read_full(&mut reader, &mut buffer)?;
+        if read < TAG_SIZE {
+            return Err(AttachmentError::Truncated);
+        }
+        let last = read < CHUNK_SIZE + TAG_SIZE;
+        let chunk = &buffer[..read];
+        hasher.update(chunk);
+
+        let plaintext = cipher.decrypt(&chunk_nonce(index, last), chunk).map_err(|_| AttachmentError::Decryption)?;
+        writer.write_all(&plaintext)?;
+        size += plaintext.len() as u64;
+        index += 1;
+
+        if last {
+            break;
+        }
+    }
+    writer.flush()?;
+
+    if URL_SAFE.encode(hasher.finalize()) != descriptor.hash || size != descriptor.size {
+        return Err(AttachmentError::Integrity);
+    }
+    Ok(size)
+}
+
+/// Split an encrypted blob in signed upload packets for the relay
+pub fn upload_packets(blob_id: &str, blob: &[u8], author_public: &str, author_private: &str) -> Result<Vec<Packet>, PacketGenerationError> {
+    let chunks: Vec<&[u8]> = if blob.is_empty() { vec![blob] } else { blob.chunks(CHUNK_SIZE + TAG_SIZE).collect() };
+    let count = chunks.len();
+
+    chunks.into_iter().enumerate().map(|(index, chunk)| {
+        let mut packet = Packet::BlobUpload(BlobChunkData {
+            headers: PacketHeader {
+                action: String::from("blob_upload"),
+                author_key: author_public.to_string(),
+                signature: String::default(),
+                ..Default::default()
+            },
+            blob_id: blob_id.to_string(),
+            offset: (index * (CHUNK_SIZE + TAG_SIZE)) as u64,
+            last: index + 1 == count,
+            data: URL_SAFE.encode(chunk),
+        });
+        sign_packet(&mut packet, author_private)?;
+        Ok(packet)
+    }).collect()
+}
+
+/// Encrypt a message payload (text and attachments descriptors) with the shared key of the
+/// recipient, padded following the conversation setting when the recipient `capabilities` include
+/// "padding". The result goes in `MessageData.content`
+pub fn encrypt_message_payload(payload: &MessagePayload, shared_key: &str, padding: Padding, capabilities: &[String]) -> Result<String, PacketGenerationError> {
+    let message = serde_json::to_string(payload)?;
+    if capabilities.iter().any(|capability| capability == PADDING) {
+        encrypt_padded_payload(&message, shared_key, padding)
+    } else {
+        encrypt_payload(&message, shared_key)
+    }
+}
+
+/// Decrypt the content of a message, `capabilities` being the ones negotiated with its author.
+/// Contents that are not a [`MessagePayload`] are read as text without attachments.
+pub fn decrypt_message_payload(content: &str, shared_key: &str, capabilities: &[String]) -> Result<MessagePayload, PacketReadingError> {
+    let plaintext = decrypt_padded_payload(content, shared_key, capabilities)?;
+    Ok(serde_json::from_str(&plaintext).unwrap_or(MessagePayload { text: plaintext, attachments: Vec::new() }))
+}
+
+impl From<std::io::Error> for AttachmentError {
+    fn from(err: std::io::Error) -> Self {
+        AttachmentError::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decrypt_attachment, encrypt_attachment, AttachmentError, CHUNK_SIZE};
+
+    #[test]
+    fn test_attachment_round_trip() {
+        for size in [0, 10, CHUNK_SIZE, CHUNK_SIZE * 2 + 5] {
+            let file: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let mut blob = Vec::new();
+            let descriptor = encrypt_attachment(file.as_slice(), &mut blob, "application/octet-stream", "file.bin").expect("Unable to encrypt attachment");
+            assert_eq!(descriptor.size, size as u64);
+
+            let mut decrypted = Vec::new();
+            decrypt_attachment(blob.as_slice(), &mut decrypted, &descriptor).expect("Unable to decrypt attachment");
+            assert_eq!(decrypted, file);
+        }
+    }
+
+    #[test]
+    fn test_truncated_attachment() {
+        let file = vec![7u8; CHUNK_SIZE * 2];
+        let mut blob = Vec::new();
+        let descriptor = encrypt_attachment(file.as_slice(), &mut blob, "text/plain", "file.txt").expect("Unable to encrypt attachment");
+
+        // the empty final chunk is missing
+        let result = decrypt_attachment(&blob[..blob.len() - 16], &mut Vec::new(), &descriptor);
+        assert!(matches!(result, Err(AttachmentError::Truncated)));
+
+        // a cut in the middle of a chunk makes it look like the last one
+        let result = decrypt_attachment(&blob[..CHUNK_SIZE], &mut Vec::new(), &descriptor);
+        assert!(matches!(result, Err(AttachmentError::Decryption)));
+    }
+}
@@ -1,25 +1,106 @@
 use std::{collections::HashMap, env, fs::{self, File}, io::BufReader};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use crate::{devices::DeviceCertificate, encryption::pseudonym::RelayPseudonym};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Config {
     #[serde(rename = "@me")]
     pub me: Me,
     pub friends: HashMap<String, Friend>,
-    pub friend_requests: HashMap<String, FriendRequest>
+    pub friend_requests: HashMap<String, FriendRequest>,
+    #[serde(default)]
+    pub revoked_keys: Vec<String>,
+    #[serde(default)]
+    pub groups: HashMap<String, Group>,
+    /// Recovery shares held for friends, by public ed25519 key of their owner
+    #[serde(default)]
+    pub held_shares: HashMap<String, HeldShare>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Friend {
     pub private_x: String,
     pub public_ed: String,
     pub shared_key: String,
     pub username: String,
     pub profile_picture: String,
-    pub last_sync: String // May be modified to a date format
+    pub last_sync: String, // May be modified to a date format
+    #[serde(default)]
+    pub public_published: String,
+    /// Set once the safety number of this friend has been compared out of band
+    #[serde(default)]
+    pub verified: bool,
+    /// Key presented for this friend that differs from the pinned one, sending is blocked until the
+    /// user accepts it
+    #[serde(default)]
+    pub pending_key_change: Option<PinnedKey>,
+    #[serde(default)]
+    pub key_history: Vec<PinnedKey>,
+    #[serde(default)]
+    pub devices: Vec<DeviceCertificate>,
+    #[serde(default)]
+    pub authentication: MessageAuthentication,
+    /// Token to present to the relay when sending sealed messages to this friend
+    #[serde(default)]
+    pub delivery_token: String,
+    /// Login key used by this friend on each relay, indexed by relay address
+    #[serde(default)]
+    pub pseudonyms: HashMap<String, String>,
+    #[serde(default)]
+    pub padding: Padding,
+    /// Protocol version and capabilities negotiated with this friend during the handshake
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// How the content of the messages exchanged with a friend is authenticated.
+/// `Deniable` messages are authenticated with a MAC derived from the shared key, which both friends
+/// can compute, so a message can't prove to a third party who wrote it. The ed25519 signature then
+/// only covers the headers the relay needs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageAuthentication {
+    #[default]
+    #[serde(rename = "signature")]
+    Signature,
+    #[serde(rename = "deniable")]
+    Deniable,
+}
+
+/// How the content of the messages of a conversation is padded before encryption, to hide its
+/// length. `Padme` has an overhead of at most 12%, `Buckets` rounds up to 256B, 1KiB, 4KiB, 16KiB,
+/// 64KiB then to multiples of 64KiB.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Padding {
+    #[serde(rename = "none")]
+    None,
+    #[default]
+    #[serde(rename = "padme")]
+    Padme,
+    #[serde(rename = "buckets")]
+    Buckets,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    #[serde(rename = "identity")]
+    Identity,
+    #[serde(rename = "published")]
+    Published,
+}
+
+/// A key of a friend along with the time (unix timestamp in seconds) it was presented as a change,
+/// or replaced when stored in the history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PinnedKey {
+    pub kind: KeyKind,
+    pub key: String,
+    pub changed_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Me {
     pub public_ed_path: String,
     pub private_ed_path: String,
@@ -27,6 +108,94 @@ pub struct Me {
     pub public_published_path: String,
     pub username: String,
     pub profile_picture: String,
+    #[serde(default)]
+    pub previous_published: Vec<PreviousPublished>,
+    /// Every device linked to this account, including this one
+    #[serde(default)]
+    pub devices: Vec<DeviceCertificate>,
+    #[serde(default)]
+    pub device: Option<LocalDevice>,
+    /// Published post-quantum KEM keys, empty when the hybrid key agreement is not enabled
+    #[serde(default)]
+    pub public_kem_published_path: String,
+    #[serde(default)]
+    pub private_kem_published_path: String,
+    /// Last social recovery backup distributed to friends
+    #[serde(default)]
+    pub recovery: Option<RecoverySetup>,
+    /// Token given to friends so that they can send us sealed messages
+    #[serde(default)]
+    pub delivery_token: String,
+    /// Login key certificates of every relay we use, indexed by relay address
+    #[serde(default)]
+    pub pseudonyms: HashMap<String, RelayPseudonym>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RecoverySetup {
+    pub backup_id: String,
+    pub threshold: u8,
+    pub holders: Vec<String>,
+}
+
+/// Share of a friend identity backup. `backup` is the identity key encrypted with the key the
+/// shares are made of, it is useless without `threshold` shares.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HeldShare {
+    pub backup_id: String,
+    pub threshold: u8,
+    pub share: String,
+    pub backup: String,
+    pub received_at: u64,
+}
+
+/// Keys of the device running this configuration. The certificate is set once the identity
+/// accepted the link request.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LocalDevice {
+    pub device_id: String,
+    pub public_ed_path: String,
+    pub private_ed_path: String,
+    pub public_published_path: String,
+    pub private_published_path: String,
+    pub certificate: Option<DeviceCertificate>,
+    /// Transaction of the link request waiting for an answer
+    pub link_transaction: Option<String>,
+    /// Identity key this device asked to be linked to, only an answer from this identity is accepted
+    #[serde(default)]
+    pub link_identity: String,
+}
+
+/// A published key that has been rotated out but is still accepted until `expires_at` (unix
+/// timestamp in seconds)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreviousPublished {
+    pub public_published: String,
+    pub private_published_path: String,
+    pub expires_at: u64,
+}
+
+/// Group conversation, members and admins are stored by their public ed25519 key.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub members: Vec<String>,
+    pub admins: Vec<String>,
+    /// Incremented on every membership change, messages must use the sender keys of this epoch
+    pub epoch: u64,
+    /// Our own sender key for the current epoch
+    pub sender_key: String,
+    /// Sender keys received from the other members, by public ed25519 key
+    pub member_sender_keys: HashMap<String, SenderKey>,
+    #[serde(default)]
+    pub padding: Padding,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SenderKey {
+    pub epoch: u64,
+    pub key: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -36,6 +205,9 @@ pub struct FriendRequest {
     pub username: String,
     pub profile_picture: String,
 }
+
+/// Keys and profile of the user, used to build client packets, see
+/// [`crate::packets::client::ClientPacketGeneration`]
 pub struct UserInformation<'a > {
     pub author_public_ed: &'a str,
     pub author_private_ed: &'a str,
@@ -45,6 +217,55 @@ pub struct UserInformation<'a > {
     pub profile_picture: &'a str
 }
 
+/// Owned version of [`UserInformation`], with the keys read from the files of [`Me`]
+#[derive(Clone)]
+pub struct Profile {
+    pub public_ed: String,
+    pub private_ed: String,
+    pub public_published: String,
+    pub private_published: String,
+    pub username: String,
+    pub profile_picture: String,
+}
+
+/// Private keys are left out so that logging a profile can't leak them
+impl std::fmt::Debug for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Profile")
+            .field("public_ed", &self.public_ed)
+            .field("private_ed", &"<redacted>")
+            .field("public_published", &self.public_published)
+            .field("private_published", &"<redacted>")
+            .field("username", &self.username)
+            .field("profile_picture", &self.profile_picture)
+            .finish()
+    }
+}
+
+impl Profile {
+    pub fn load(me: &Me) -> std::io::Result<Self> {
+        Ok(Self {
+            public_ed: fs::read_to_string(&me.public_ed_path)?,
+            private_ed: fs::read_to_string(&me.private_ed_path)?,
+            public_published: fs::read_to_string(&me.public_published_path)?,
+            private_published: fs::read_to_string(&me.private_published_path)?,
+            username: me.username.clone(),
+            profile_picture: me.profile_picture.clone(),
+        })
+    }
+
+    pub fn user_information(&self) -> UserInformation<'_> {
+        UserInformation {
+            author_public_ed: &self.public_ed,
+            author_private_ed: &self.private_ed,
+            author_public_published: &self.public_published,
+            author_private_published: &self.private_published,
+            username: &self.username,
+            profile_picture: &self.profile_picture,
+        }
+    }
+}
+
 /// This function update the config file of the user. 
 /// Arguments : 
 /// Config config = the new config to be written.
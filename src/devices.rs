@@ -0,0 +1,463 @@
+use std::{env, fmt::Display, fs, str::FromStr};
+
+use ed25519_dalek::{Signature as EdSignature, Signer, SigningKey, pkcs8::DecodePrivateKey};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{config::{Config, Friend, LocalDevice}, current_timestamp, encryption::{deniable::message_authentication, keys::{decode_ed_public, ed_public_pem_to_raw, generate_ed_keys, generate_x_keys, same_ed_key}, revocation::is_revoked, signature::{sign_packet, verify_packet, verify_packet_signature}}, packets::{DeviceLinkRequestData, DeviceLinkResponseData, DeviceListData, MessageData, Packet, PacketGenerationError, PacketHeader, PacketReadingError}, transactions::{self, StorageError, Transaction, TransactionType}};
+
+/// Device sub-keys certified by the identity ed25519 key of an account.
+/// Messages are signed by the device key and encrypted for each device published key.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct DeviceCertificate {
+    pub identity_key: String,
+    pub device_id: String,
+    pub device_ed: String,
+    pub device_published: String,
+    pub created_at: u64,
+    pub signature: String,
+}
+
+impl DeviceCertificate {
+    pub fn get_signature_payload(&self) -> String {
+        format!("device{}{}{}{}{}", self.identity_key, self.device_id, self.device_ed, self.device_published, self.created_at)
+    }
+}
+
+/// Signature payload of a list of certificates, used by the packets carrying them
+pub fn devices_signature_payload(devices: &[DeviceCertificate]) -> String {
+    devices.iter().map(|device| format!("{}{}", device.get_signature_payload(), device.signature)).collect()
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    Io(std::io::Error),
+    Storage(StorageError),
+    Generation(PacketGenerationError),
+    WrongPacket,
+    InvalidRequest,
+    InvalidCertificate,
+    /// Packet addressed to another key than ours
+    WrongRecipient,
+    Refused,
+    NoPendingLink,
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceError::Io(e) => {
+                write!(f, "IO error: {}", e)
+            }
+            DeviceError::Storage(e) => {
+                write!(f, "Transaction error: {}", e)
+            }
+            DeviceError::Generation(e) => {
+                write!(f, "Unable to generate device packet: {}", e)
+            }
+            DeviceError::WrongPacket => {
+                write!(f, "Unexpected packet type for this device operation")
+            }
+            DeviceError::InvalidRequest => {
+                write!(f, "Device link request has an invalid signature")
+            }
+            DeviceError::InvalidCertificate => {
+                write!(f, "Device certificate is not signed by the expected identity")
+            }
+            DeviceError::WrongRecipient => {
+                write!(f, "Device packet is addressed to another key")
+            }
+            DeviceError::Refused => {
+                write!(f, "Device link has been refused")
+            }
+            DeviceError::NoPendingLink => {
+                write!(f, "This device has no pending link request")
+            }
+        }
+    }
+}
+impl std::error::Error for DeviceError {}
+
+/// Certify a device with the identity key of the account
+pub fn certify_device(identity_private: &str, identity_public: &str, device_id: &str, device_ed: &str, device_published: &str) -> Result<DeviceCertificate, PacketGenerationError> {
+    let key = SigningKey::from_pkcs8_pem(identity_private)?;
+
+    let mut certificate = DeviceCertificate {
+        identity_key: identity_public.to_string(),
+        device_id: device_id.to_string(),
+        device_ed: device_ed.to_string(),
+        device_published: device_published.to_string(),
+        created_at: current_timestamp(),
+        signature: String::default(),
+    };
+    certificate.signature = key.sign(certificate.get_signature_payload().as_bytes()).to_string();
+
+    Ok(certificate)
+}
+
+/// Verify that a device certificate has been issued by `identity_key`, and that neither the
+/// identity nor the device key is among `revoked_keys`
+pub fn verify_device_certificate(certificate: &DeviceCertificate, identity_key: &str, revoked_keys: &[String]) -> Result<(), PacketReadingError> {
+    let identity = decode_ed_public(identity_key).map_err(|e| e.with_field("identity_key"))?;
+    if decode_ed_public(&certificate.identity_key).map_err(|e| e.with_field("certificate.identity_key"))? != identity {
+        return Err(PacketReadingError::key("certificate.identity_key"));
+    }
+
+    let device = decode_ed_public(&certificate.device_ed).map_err(|e| e.with_field("certificate.device_ed"))?;
+    if is_revoked(revoked_keys, &identity) {
+        return Err(PacketReadingError::Revoked { action: String::new(), field: String::from("certificate.identity_key") });
+    }
+    if is_revoked(revoked_keys, &device) {
+        return Err(PacketReadingError::Revoked { action: String::new(), field: String::from("certificate.device_ed") });
+    }
+
+    let signature = EdSignature::from_str(&certificate.signature)?;
+    identity.verify_strict(certificate.get_signature_payload().as_bytes(), &signature)?;
+    Ok(())
+}
+
+/// Build the signed list of the account devices, to be sent to the relay. Relays read compact keys,
+/// our key is always written in that form.
+pub fn device_list_packet(config: &Config) -> Result<Packet, DeviceError> {
+    let public_ed = fs::read_to_string(&config.me.public_ed_path)?;
+    let private_ed = fs::read_to_string(&config.me.private_ed_path)?;
+
+    let mut packet = Packet::DeviceList(DeviceListData {
+        headers: PacketHeader {
+            action: String::from("device_list"),
+            author_key: ed_public_pem_to_raw(&public_ed).map_err(|e| PacketGenerationError::ed_key("me.public_ed_path").with_source(e))?,
+            signature: String::default(),
+            ..Default::default()
+        },
+        devices: config.me.devices.clone(),
+    });
+    sign_packet(&mut packet, &private_ed)?;
+    Ok(packet)
+}
+
+/// First step of the link process, on the new device.
+/// Generate the device keys, store a transaction waiting for the answer and return the request to
+/// send to the identity.
+pub fn request_device_link(config: &mut Config, identity_public: &str) -> Result<Packet, DeviceError> {
+    let config_path = env::var("PLUME_CONFIG").map_err(StorageError::from)?;
+    let device_id = Uuid::new_v4().to_string();
+
+    let (private_ed, public_ed) = generate_ed_keys();
+    let (private_published, public_published) = generate_x_keys();
+    let device = LocalDevice {
+        device_id: device_id.clone(),
+        public_ed_path: format!("{config_path}/keys/device_public_ed.pem"),
+        private_ed_path: format!("{config_path}/keys/device_private_ed.pem"),
+        public_published_path: format!("{config_path}/keys/device_public_published.pem"),
+        private_published_path: format!("{config_path}/keys/device_private_published.pem"),
+        certificate: None,
+        link_transaction: Some(transactions::store(Transaction::new(TransactionType::DeviceLink, identity_public))?),
+        link_identity: identity_public.to_string(),
+    };
+    fs::write(&device.public_ed_path, &public_ed)?;
+    fs::write(&device.private_ed_path, &private_ed)?;
+    fs::write(&device.public_published_path, &public_published)?;
+    fs::write(&device.private_published_path, private_published)?;
+    config.me.device = Some(device);
+
+    let mut packet = Packet::DeviceLinkRequest(DeviceLinkRequestData {
+        headers: PacketHeader {
+            action: String::from("device_link_request"),
+            author_key: public_ed,
+            signature: String::default(),
+            ..Default::default()
+        },
+        recipient: identity_public.to_string(),
+        device_id,
+        device_published: public_published,
+    });
+    sign_packet(&mut packet, &private_ed)?;
+    Ok(packet)
+}
+
+/// Second step of the link process, on a device holding the identity key.
+/// When accepted, the new device is added to the account devices, the device list must then be sent
+/// again to the relay with [`device_list_packet`].
+pub fn answer_device_link(config: &mut Config, request: &Packet, accept: bool) -> Result<Packet, DeviceError> {
+    let Packet::DeviceLinkRequest(data) = request else {
+        return Err(DeviceError::WrongPacket);
+    };
+    verify_packet(config, request).map_err(|_| DeviceError::InvalidRequest)?;
+
+    let public_ed = fs::read_to_string(&config.me.public_ed_path)?;
+    let private_ed = fs::read_to_string(&config.me.private_ed_path)?;
+    if !same_ed_key(&data.recipient, &public_ed) {
+        return Err(DeviceError::WrongRecipient);
+    }
+
+    let certificate = if accept {
+        let certificate = certify_device(&private_ed, &public_ed, &data.device_id, &data.headers.author_key, &data.device_published)?;
+        config.me.devices.push(certificate.clone());
+        certificate
+    } else {
+        DeviceCertificate::default()
+    };
+
+    let mut packet = Packet::DeviceLinkResponse(DeviceLinkResponseData {
+        headers: PacketHeader {
+            action: String::from("device_link_response"),
+            author_key: public_ed,
+            signature: String::default(),
+            ..Default::default()
+        },
+        recipient: data.headers.author_key.clone(),
+        accepted: accept,
+        certificate,
+    });
+    sign_packet(&mut packet, &private_ed)?;
+    Ok(packet)
+}
+
+/// Last step of the link process, on the new device: store the certificate and close the
+/// transaction. Only an answer of the identity the link was requested to is accepted, whether it
+/// accepts or refuses the link.
+pub fn complete_device_link(config: &mut Config, response: &Packet) -> Result<(), DeviceError> {
+    let Packet::DeviceLinkResponse(data) = response else {
+        return Err(DeviceError::WrongPacket);
+    };
+    verify_packet(config, response).map_err(|_| DeviceError::InvalidCertificate)?;
+    let revoked_keys = config.revoked_keys.clone();
+    let device = config.me.device.as_mut().ok_or(DeviceError::NoPendingLink)?;
+    let transaction_id = device.link_transaction.clone().ok_or(DeviceError::NoPendingLink)?;
+
+    let device_ed = fs::read_to_string(&device.public_ed_path)?;
+    if !same_ed_key(&data.recipient, &device_ed) {
+        return Err(DeviceError::WrongRecipient);
+    }
+    if !same_ed_key(&data.headers.author_key, &device.link_identity) {
+        return Err(DeviceError::InvalidCertificate);
+    }
+
+    if !data.accepted {
+        transactions::delete(&transaction_id)?;
+        device.link_transaction = None;
+        device.link_identity = String::default();
+        return Err(DeviceError::Refused);
+    }
+
+    if !same_ed_key(&data.certificate.device_ed, &device_ed)
+        || !same_ed_key(&data.certificate.identity_key, &device.link_identity)
+        || data.certificate.device_id != device.device_id {
+        return Err(DeviceError::InvalidCertificate);
+    }
+    verify_device_certificate(&data.certificate, &data.headers.author_key, &revoked_keys).map_err(|_| DeviceError::InvalidCertificate)?;
+
+    transactions::delete(&transaction_id)?;
+    device.link_transaction = None;
+    device.link_identity = String::default();
+    device.certificate = Some(data.certificate.clone());
+    if !config.me.devices.contains(&data.certificate) {
+        config.me.devices.push(data.certificate.clone());
+    }
+    Ok(())
+}
+
+/// Store the devices of `friend`, served by the relay in a `RetrievePublished` answer or sent by the
+/// friend in a `DeviceList`. Only the certificates issued by the friend identity and involving no
+/// key of `revoked_keys` are kept. The signature of a `RetrievePublished` answer must have been
+/// verified, it is written by the relay.
+pub fn store_friend_devices(friend: &mut Friend, packet: &Packet, revoked_keys: &[String]) -> Result<(), DeviceError> {
+    let devices = match packet {
+        Packet::RetrievePublished(data) => {
+            if !same_ed_key(&data.recipient, &friend.public_ed) {
+                return Err(DeviceError::WrongRecipient);
+            }
+            &data.devices
+        }
+        Packet::DeviceList(data) => {
+            if !same_ed_key(&data.headers.author_key, &friend.public_ed) {
+                return Err(DeviceError::InvalidCertificate);
+            }
+            verify_packet_signature(packet, revoked_keys).map_err(|_| DeviceError::InvalidCertificate)?;
+            &data.devices
+        }
+        _ => return Err(DeviceError::WrongPacket),
+    };
+
+    friend.devices = devices.iter()
+        .filter(|device| verify_device_certificate(device, &friend.public_ed, revoked_keys).is_ok())
+        .cloned()
+        .collect();
+    Ok(())
+}
+
+/// Whether `key` is the identity key of `friend` or the key of one of its certified devices, see
+/// [`store_friend_devices`]
+pub fn is_friend_key(friend: &Friend, key: &str, revoked_keys: &[String]) -> bool {
+    same_ed_key(key, &friend.public_ed)
+        || friend.devices.iter().any(|device| same_ed_key(&device.device_ed, key) && verify_device_certificate(device, &friend.public_ed, revoked_keys).is_ok())
+}
+
+/// Copy a message for each valid device of `friend`, its recipient. `encrypt` returns the content
+/// encrypted for the given device, the MAC of deniable conversations is computed for each copy.
+/// The copies still need to be signed by the sending device.
+/// Certificates that are not issued by the recipient identity, or involving a key of
+/// `revoked_keys`, are skipped.
+pub fn fan_out_message<E: From<PacketGenerationError>>(message: &MessageData, friend: &Friend, revoked_keys: &[String], mut encrypt: impl FnMut(&DeviceCertificate) -> Result<String, E>) -> Result<Vec<MessageData>, E> {
+    let mut messages = Vec::with_capacity(friend.devices.len());
+
+    for device in friend.devices.iter().filter(|device| verify_device_certificate(device, &message.recipient, revoked_keys).is_ok()) {
+        let mut copy = MessageData {
+            headers: PacketHeader {
+                action: message.headers.action.clone(),
+                author_key: message.headers.author_key.clone(),
+                signature: String::default(),
+                ..Default::default()
+            },
+            recipient: message.recipient.clone(),
+            sent_at: message.sent_at.clone(),
+            content: encrypt(device)?,
+            recipient_device: device.device_id.clone(),
+            mac: String::default(),
+        };
+        copy.mac = message_authentication(&copy, friend)?;
+        messages.push(copy);
+    }
+
+    Ok(messages)
+}
+
+impl From<std::io::Error> for DeviceError {
+    fn from(err: std::io::Error) -> Self {
+        DeviceError::Io(err)
+    }
+}
+
+impl From<StorageError> for DeviceError {
+    fn from(err: StorageError) -> Self {
+        DeviceError::Storage(err)
+    }
+}
+
+impl From<PacketGenerationError> for DeviceError {
+    fn from(err: PacketGenerationError) -> Self {
+        DeviceError::Generation(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs};
+
+    use crate::{config::{Config, Friend, LocalDevice, MessageAuthentication}, encryption::{deniable::{message_authentication, verify_message}, keys::{generate_ed_keys, generate_shared_key, generate_x_keys}, signature::sign_packet}, packets::{DeviceLinkResponseData, DeviceListData, MessageData, Packet, PacketGenerationError, PacketHeader}};
+
+    use super::{certify_device, complete_device_link, fan_out_message, store_friend_devices, DeviceError};
+
+    #[test]
+    fn test_fan_out_skips_foreign_devices() {
+        let (identity_private, identity_public) = generate_ed_keys();
+        let (other_private, other_public) = generate_ed_keys();
+        let (_, device_ed) = generate_ed_keys();
+        let (_, device_published) = generate_x_keys();
+        let (private_x, public_x) = generate_x_keys();
+
+        let friend = Friend {
+            public_ed: identity_public.clone(),
+            shared_key: generate_shared_key(&private_x, &public_x).expect("Unable to generate shared key"),
+            authentication: MessageAuthentication::Deniable,
+            devices: vec![
+                certify_device(&identity_private, &identity_public, "phone", &device_ed, &device_published).expect("Unable to certify device"),
+                certify_device(&other_private, &other_public, "intruder", &device_ed, &device_published).expect("Unable to certify device"),
+            ],
+            ..Default::default()
+        };
+        let message = MessageData { recipient: identity_public, content: String::from("hello"), ..Default::default() };
+
+        let copies = fan_out_message(&message, &friend, &[], |device| Ok::<_, PacketGenerationError>(device.device_id.clone())).expect("Fan out failed");
+        assert_eq!(copies.len(), 1);
+        assert_eq!(copies[0].recipient_device, "phone");
+        assert_eq!(copies[0].content, "phone");
+        // deniable conversations keep their MAC, computed for the copy
+        assert_eq!(copies[0].mac, message_authentication(&copies[0], &friend).expect("Unable to compute mac"));
+        assert!(!copies[0].mac.is_empty());
+    }
+
+    #[test]
+    fn test_message_from_friend_device() {
+        let (identity_private, identity_public) = generate_ed_keys();
+        let (device_private, device_ed) = generate_ed_keys();
+        let (_, device_published) = generate_x_keys();
+        let (other_private, other_public) = generate_ed_keys();
+
+        let certificate = certify_device(&identity_private, &identity_public, "phone", &device_ed, &device_published).expect("Unable to certify device");
+        let foreign = certify_device(&other_private, &other_public, "intruder", &device_ed, &device_published).expect("Unable to certify device");
+        let mut list = Packet::DeviceList(DeviceListData {
+            headers: PacketHeader { action: String::from("device_list"), author_key: identity_public.clone(), ..Default::default() },
+            devices: vec![certificate.clone(), foreign],
+        });
+        sign_packet(&mut list, &identity_private).expect("Unable to sign device list");
+
+        let mut friend = Friend { public_ed: identity_public.clone(), ..Default::default() };
+        let mut message = Packet::Message(MessageData {
+            headers: PacketHeader { action: String::from("message"), author_key: device_ed.clone(), ..Default::default() },
+            content: String::from("hello"),
+            ..Default::default()
+        });
+        sign_packet(&mut message, &device_private).expect("Unable to sign message");
+        assert!(verify_message(&message, &friend, &[]).is_err());
+
+        store_friend_devices(&mut friend, &list, &[]).expect("Unable to store devices");
+        assert_eq!(friend.devices, vec![certificate]);
+        assert!(verify_message(&message, &friend, &[]).is_ok());
+        assert!(verify_message(&message, &friend, &[device_ed]).is_err());
+
+        // only the friend can send its device list
+        let mut forged = Packet::DeviceList(DeviceListData {
+            headers: PacketHeader { action: String::from("device_list"), author_key: other_public, ..Default::default() },
+            devices: Vec::new(),
+        });
+        sign_packet(&mut forged, &other_private).expect("Unable to sign device list");
+        assert!(matches!(store_friend_devices(&mut friend, &forged, &[]), Err(DeviceError::InvalidCertificate)));
+        assert_eq!(friend.devices.len(), 1);
+    }
+
+    #[test]
+    fn test_link_response_of_another_identity_refused() {
+        let (_, identity_public) = generate_ed_keys();
+        let (intruder_private, intruder_public) = generate_ed_keys();
+        let (_, device_ed) = generate_ed_keys();
+        let (_, device_published) = generate_x_keys();
+
+        let dir = env::temp_dir().join(format!("plume_devices_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).expect("Unable to create key directory");
+        let public_ed_path = dir.join("device_ed.pem").to_string_lossy().to_string();
+        fs::write(&public_ed_path, &device_ed).expect("Unable to write key");
+
+        let mut config = Config::default();
+        config.me.device = Some(LocalDevice {
+            device_id: String::from("laptop"),
+            public_ed_path,
+            link_transaction: Some(String::from("pending")),
+            link_identity: identity_public,
+            ..Default::default()
+        });
+
+        let response = |accepted: bool| {
+            let mut packet = Packet::DeviceLinkResponse(DeviceLinkResponseData {
+                headers: PacketHeader {
+                    action: String::from("device_link_response"),
+                    author_key: intruder_public.clone(),
+                    ..Default::default()
+                },
+                recipient: device_ed.clone(),
+                accepted,
+                certificate: certify_device(&intruder_private, &intruder_public, "laptop", &device_ed, &device_published).expect("Unable to certify device"),
+            });
+            sign_packet(&mut packet, &intruder_private).expect("Unable to sign response");
+            packet
+        };
+
+        assert!(matches!(complete_device_link(&mut config, &response(true)), Err(DeviceError::InvalidCertificate)));
+        assert!(matches!(complete_device_link(&mut config, &response(false)), Err(DeviceError::InvalidCertificate)));
+        let device = config.me.device.as_ref().expect("Device removed from the configuration");
+        assert!(device.link_transaction.is_some());
+        assert!(device.certificate.is_none());
+
+        fs::remove_dir_all(&dir).expect("Unable to delete key directory");
+    }
+}
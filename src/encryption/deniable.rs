@@ -0,0 +1,103 @@
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{config::{Friend, MessageAuthentication}, devices::is_friend_key, encryption::{keys::ed_key_id, signature::{sign_packet, verify_packet_signature}}, packets::{MessageData, Packet, PacketGenerationError, PacketReadingError}};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// MAC key derived from the shared key, kept separate from the encryption key
+fn message_mac(message: &MessageData, shared_key: &str) -> Option<HmacSha256> {
+    let decoded: [u8; 32] = URL_SAFE.decode(shared_key).ok()?.try_into().ok()?;
+    let key = Sha256::new().chain_update(b"plume_mac").chain_update(decoded).finalize();
+
+    let mut mac = HmacSha256::new_from_slice(&key).ok()?;
+    // the author key is rewritten in its compact form when signing, both encodings must give the same mac
+    mac.update(ed_key_id(&message.headers.author_key).as_bytes());
+    mac.update(message.recipient.as_bytes());
+    mac.update(message.sent_at.as_bytes());
+    mac.update(message.content.as_bytes());
+    mac.update(message.recipient_device.as_bytes());
+    Some(mac)
+}
+
+/// Value of `MessageData.mac` following the authentication mode chosen for `friend`, empty when
+/// the content is covered by the signature
+pub fn message_authentication(message: &MessageData, friend: &Friend) -> Result<String, PacketGenerationError> {
+    match friend.authentication {
+        MessageAuthentication::Signature => Ok(String::default()),
+        MessageAuthentication::Deniable => {
+            let mac = message_mac(message, &friend.shared_key).ok_or_else(|| PacketGenerationError::shared_key("shared_key").with_action("message"))?;
+            Ok(URL_SAFE.encode(mac.finalize().into_bytes()))
+        }
+    }
+}
+
+/// Authenticate and sign a message following the authentication mode chosen for `friend`.
+/// In deniable mode the content is covered by a MAC only, the signature covers the headers.
+pub fn authenticate_message(mut message: MessageData, friend: &Friend, private_key: &str) -> Result<Packet, PacketGenerationError> {
+    message.mac = message_authentication(&message, friend)?;
+
+    let mut packet = Packet::Message(message);
+    sign_packet(&mut packet, private_key)?;
+    Ok(packet)
+}
+
+/// Verify a message received from `friend`, written by its identity key or one of its certified
+/// devices: the signature of the headers, and the MAC of the content for deniable messages. Both
+/// modes are accepted whatever the friend setting is, a signed content being a stronger guarantee.
+/// Messages written by one of `revoked_keys` are refused.
+pub fn verify_message(packet: &Packet, friend: &Friend, revoked_keys: &[String]) -> Result<(), PacketReadingError> {
+    let Packet::Message(message) = packet else {
+        return Err(PacketReadingError::unexpected(packet));
+    };
+    if !is_friend_key(friend, &message.headers.author_key, revoked_keys) {
+        return Err(PacketReadingError::key("headers.author_key").with_action(&message.headers.action));
+    }
+    verify_packet_signature(packet, revoked_keys)?;
+
+    if message.mac.is_empty() {
+        return Ok(());
+    }
+
+    let expected = URL_SAFE.decode(&message.mac).map_err(|e| PacketReadingError::data("mac").with_action(&message.headers.action).with_source(e))?;
+    message_mac(message, &friend.shared_key)
+        .ok_or_else(|| PacketReadingError::key("shared_key").with_action(&message.headers.action))?
+        .verify_slice(&expected)
+        .map_err(|_| PacketReadingError::Signature { action: message.headers.action.clone(), source: None })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{config::{Friend, MessageAuthentication}, encryption::keys::{generate_ed_keys, generate_shared_key, generate_x_keys}, packets::{MessageData, Packet, PacketHeader, PacketReadingError}};
+
+    use super::{authenticate_message, verify_message};
+
+    #[test]
+    fn test_deniable_message() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let (private_x, _) = generate_x_keys();
+        let (_, public_x) = generate_x_keys();
+        let friend = Friend {
+            public_ed: public_ed.clone(),
+            shared_key: generate_shared_key(&private_x, &public_x).expect("Unable to generate shared key"),
+            authentication: MessageAuthentication::Deniable,
+            ..Default::default()
+        };
+
+        let message = MessageData {
+            headers: PacketHeader { action: String::from("message"), author_key: public_ed.clone(), signature: String::default(), ..Default::default() },
+            recipient: String::from("friend"),
+            content: String::from("hello"),
+            ..Default::default()
+        };
+        let mut packet = authenticate_message(message, &friend, &private_ed).expect("Unable to authenticate message");
+        assert!(verify_message(&packet, &friend, &[]).is_ok());
+        assert!(matches!(verify_message(&packet, &friend, &[public_ed]), Err(PacketReadingError::Revoked { .. })));
+
+        // the signature doesn't cover the content anymore, the mac does
+        let Packet::Message(message) = &mut packet else { unreachable!() };
+        message.content = String::from("tampered");
+        assert!(verify_message(&packet, &friend, &[]).is_err());
+    }
+}
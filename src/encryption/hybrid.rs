@@ -0,0 +1,203 @@
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Me, encryption::keys::{generate_shared_key, SharedGenerationError}};
+#[cfg(feature = "mlkem")]
+use ml_kem::{kem::{Decapsulate, Encapsulate}, Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768Params};
+#[cfg(feature = "mlkem")]
+use rand_core::OsRng;
+
+#[cfg(feature = "mlkem")]
+type EncapsulationKey = ml_kem::kem::EncapsulationKey<MlKem768Params>;
+#[cfg(feature = "mlkem")]
+type DecapsulationKey = ml_kem::kem::DecapsulationKey<MlKem768Params>;
+
+/// Capability advertised by clients able to combine x25519 with ML-KEM-768
+pub const HYBRID_CAPABILITY: &str = "hybrid_x25519_mlkem768";
+
+/// Key encapsulation mechanism used for the post-quantum half of the hybrid key agreement.
+/// Keys and ciphertexts are exchanged as url safe base64 like the x25519 keys. An ML-KEM-768
+/// implementation is provided by [`MlKem768`] with the `mlkem` feature.
+pub trait Kem {
+    /// Generate a key pair and returns it in this order : (decapsulation_key, encapsulation_key)
+    fn generate_keys() -> (String, String);
+    /// Returns the ciphertext to send to the owner of `encapsulation_key` and the shared secret
+    fn encapsulate(encapsulation_key: &str) -> Result<(String, [u8; 32]), SharedGenerationError>;
+    fn decapsulate(decapsulation_key: &str, ciphertext: &str) -> Result<[u8; 32], SharedGenerationError>;
+}
+
+/// ML-KEM-768 (FIPS 203), keys are stored in their encoded form
+#[cfg(feature = "mlkem")]
+pub struct MlKem768;
+
+#[cfg(feature = "mlkem")]
+impl Kem for MlKem768 {
+    fn generate_keys() -> (String, String) {
+        let (decapsulation_key, encapsulation_key) = ml_kem::MlKem768::generate(&mut OsRng);
+        (URL_SAFE.encode(decapsulation_key.as_bytes()), URL_SAFE.encode(encapsulation_key.as_bytes()))
+    }
+
+    fn encapsulate(encapsulation_key: &str) -> Result<(String, [u8; 32]), SharedGenerationError> {
+        let encoded = URL_SAFE.decode(encapsulation_key)?;
+        let encoded = Encoded::<EncapsulationKey>::try_from(encoded.as_slice()).map_err(|_| SharedGenerationError::InvalidKeyError)?;
+        let (ciphertext, shared) = EncapsulationKey::from_bytes(&encoded).encapsulate(&mut OsRng).map_err(|_| SharedGenerationError::KemError)?;
+        Ok((URL_SAFE.encode(ciphertext), shared.into()))
+    }
+
+    fn decapsulate(decapsulation_key: &str, ciphertext: &str) -> Result<[u8; 32], SharedGenerationError> {
+        let encoded = URL_SAFE.decode(decapsulation_key)?;
+        let encoded = Encoded::<DecapsulationKey>::try_from(encoded.as_slice()).map_err(|_| SharedGenerationError::InvalidKeyError)?;
+        let ciphertext = URL_SAFE.decode(ciphertext)?;
+        let ciphertext = Ciphertext::<ml_kem::MlKem768>::try_from(ciphertext.as_slice()).map_err(|_| SharedGenerationError::KemError)?;
+        let shared = DecapsulationKey::from_bytes(&encoded).decapsulate(&ciphertext).map_err(|_| SharedGenerationError::KemError)?;
+        Ok(shared.into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAgreement {
+    Classic,
+    Hybrid,
+}
+
+/// Capabilities to advertise in the friend request, the hybrid mode is enabled once the user has
+/// published KEM keys
+pub fn local_capabilities(me: &Me) -> Vec<String> {
+    if me.public_kem_published_path.is_empty() {
+        Vec::new()
+    } else {
+        vec![HYBRID_CAPABILITY.to_string()]
+    }
+}
+
+/// The hybrid key agreement is only used when both sides support it, classic-only peers keep
+/// using x25519 alone
+pub fn negotiate(local: &[String], remote: &[String]) -> KeyAgreement {
+    let supported = |capabilities: &[String]| capabilities.iter().any(|c| c == HYBRID_CAPABILITY);
+
+    if supported(local) && supported(remote) {
+        KeyAgreement::Hybrid
+    } else {
+        KeyAgreement::Classic
+    }
+}
+
+/// Both secrets and the KEM ciphertext go through the KDF, so the result stays secret as long as
+/// one of the two key agreements is unbroken
+fn combine(classic_shared: &str, kem_shared: &[u8; 32], kem_ciphertext: &str) -> Result<String, SharedGenerationError> {
+    let classic: [u8; 32] = URL_SAFE.decode(classic_shared)?.try_into()?;
+    let combined = Sha256::new()
+        .chain_update(b"plume_hybrid")
+        .chain_update(classic)
+        .chain_update(kem_shared)
+        .chain_update(kem_ciphertext.as_bytes())
+        .finalize();
+
+    Ok(URL_SAFE.encode(combined))
+}
+
+/// Shared key generation on the friend request author side.
+///
+/// returns !
+///
+/// (shared_key: String, kem_ciphertext: String)
+///
+/// The ciphertext is empty when the classic key agreement is used, it must be sent in the
+/// friend request otherwise. An hybrid agreement with a target that has no published KEM key is
+/// refused instead of falling back to the classic one.
+pub fn initiate_shared_key<K: Kem>(user_private: &str, target_public: &str, target_kem_public: &str, agreement: KeyAgreement) -> Result<(String, String), SharedGenerationError> {
+    let classic = generate_shared_key(user_private, target_public)?;
+
+    if agreement == KeyAgreement::Classic {
+        return Ok((classic, String::new()));
+    }
+    if target_kem_public.is_empty() {
+        return Err(SharedGenerationError::Downgrade);
+    }
+
+    let (ciphertext, kem_shared) = K::encapsulate(target_kem_public)?;
+    Ok((combine(&classic, &kem_shared, &ciphertext)?, ciphertext))
+}
+
+/// Shared key generation on the friend request recipient side. An hybrid agreement without
+/// ciphertext is refused as it would mean the request has been downgraded.
+pub fn respond_shared_key<K: Kem>(user_private: &str, author_public: &str, kem_private: &str, kem_ciphertext: &str, agreement: KeyAgreement) -> Result<String, SharedGenerationError> {
+    let classic = generate_shared_key(user_private, author_public)?;
+
+    match agreement {
+        KeyAgreement::Classic => Ok(classic),
+        KeyAgreement::Hybrid if kem_ciphertext.is_empty() => Err(SharedGenerationError::Downgrade),
+        KeyAgreement::Hybrid => {
+            let kem_shared = K::decapsulate(kem_private, kem_ciphertext)?;
+            combine(&classic, &kem_shared, kem_ciphertext)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use base64::{engine::general_purpose::URL_SAFE, Engine};
+
+    use crate::encryption::keys::{generate_shared_key, generate_x_keys, SharedGenerationError};
+
+    use super::{initiate_shared_key, negotiate, respond_shared_key, Kem, KeyAgreement, HYBRID_CAPABILITY};
+
+    /// Stand-in KEM built on x25519, only used to exercise the hybrid combination
+    struct DhKem;
+
+    impl Kem for DhKem {
+        fn generate_keys() -> (String, String) {
+            generate_x_keys()
+        }
+
+        fn encapsulate(encapsulation_key: &str) -> Result<(String, [u8; 32]), SharedGenerationError> {
+            let (ephemeral_private, ephemeral_public) = generate_x_keys();
+            let shared = URL_SAFE.decode(generate_shared_key(&ephemeral_private, encapsulation_key)?)?.try_into()?;
+            Ok((ephemeral_public, shared))
+        }
+
+        fn decapsulate(decapsulation_key: &str, ciphertext: &str) -> Result<[u8; 32], SharedGenerationError> {
+            Ok(URL_SAFE.decode(generate_shared_key(decapsulation_key, ciphertext)?)?.try_into()?)
+        }
+    }
+
+    #[test]
+    fn test_hybrid_agreement() {
+        let hybrid = vec![HYBRID_CAPABILITY.to_string()];
+        assert_eq!(negotiate(&hybrid, &[]), KeyAgreement::Classic);
+        assert_eq!(negotiate(&hybrid, &hybrid), KeyAgreement::Hybrid);
+
+        let (author_private, author_public) = generate_x_keys();
+        let (target_private, target_public) = generate_x_keys();
+        let (kem_private, kem_public) = DhKem::generate_keys();
+
+        let (shared, ciphertext) = initiate_shared_key::<DhKem>(&author_private, &target_public, &kem_public, KeyAgreement::Hybrid).expect("Unable to initiate");
+        let responded = respond_shared_key::<DhKem>(&target_private, &author_public, &kem_private, &ciphertext, KeyAgreement::Hybrid).expect("Unable to respond");
+        assert_eq!(shared, responded);
+        assert_ne!(shared, generate_shared_key(&author_private, &target_public).expect("Unable to generate shared key"));
+
+        let downgraded = respond_shared_key::<DhKem>(&target_private, &author_public, &kem_private, "", KeyAgreement::Hybrid);
+        assert!(matches!(downgraded, Err(SharedGenerationError::Downgrade)));
+        let downgraded = initiate_shared_key::<DhKem>(&author_private, &target_public, "", KeyAgreement::Hybrid);
+        assert!(matches!(downgraded, Err(SharedGenerationError::Downgrade)));
+    }
+
+    #[cfg(feature = "mlkem")]
+    #[test]
+    fn test_mlkem_agreement() {
+        use super::MlKem768;
+
+        let (author_private, author_public) = generate_x_keys();
+        let (target_private, target_public) = generate_x_keys();
+        let (kem_private, kem_public) = MlKem768::generate_keys();
+
+        let (shared, ciphertext) = initiate_shared_key::<MlKem768>(&author_private, &target_public, &kem_public, KeyAgreement::Hybrid).expect("Unable to initiate");
+        let responded = respond_shared_key::<MlKem768>(&target_private, &author_public, &kem_private, &ciphertext, KeyAgreement::Hybrid).expect("Unable to respond");
+        assert_eq!(shared, responded);
+
+        let (other_private, _) = MlKem768::generate_keys();
+        let wrong_key = respond_shared_key::<MlKem768>(&target_private, &author_public, &other_private, &ciphertext, KeyAgreement::Hybrid);
+        assert_ne!(wrong_key.ok(), Some(shared));
+        assert!(matches!(MlKem768::encapsulate("c2hvcnQ="), Err(SharedGenerationError::InvalidKeyError)));
+    }
+}
@@ -1,8 +1,13 @@
 use base64::{engine::general_purpose::URL_SAFE, DecodeError, Engine};
-use ed25519_dalek::{pkcs8::{EncodePrivateKey, EncodePublicKey}, SigningKey, VerifyingKey};
+use ed25519_dalek::{pkcs8::{DecodePublicKey, EncodePrivateKey, EncodePublicKey}, SigningKey, VerifyingKey};
 use rand_core::OsRng;
 use x25519_dalek::PublicKey;
 
+use crate::packets::PacketReadingError;
+
+/// Start of the PEM encoded keys
+const PEM_PREFIX: &str = "-----BEGIN";
+
 /// Generate a x25519 key combinaison
 ///
 /// returns !
@@ -34,12 +39,75 @@ pub fn generate_ed_keys() -> (String, String) {
 }
 
 
+/// Compact form of an ed25519 public key used on the wire: the url safe base64 of its 32 raw bytes,
+/// like the x25519 keys
+pub fn encode_ed_public(key: &VerifyingKey) -> String {
+    URL_SAFE.encode(key.as_bytes())
+}
+
+/// Read an ed25519 public key given in its compact form or in its PKCS#8 PEM form, both being
+/// accepted while clients move to the compact form. Errors point to `public_ed`, callers knowing
+/// where the key comes from replace it with [`PacketReadingError::with_field`].
+pub fn decode_ed_public(key: &str) -> Result<VerifyingKey, PacketReadingError> {
+    let key = key.trim();
+    if key.starts_with(PEM_PREFIX) {
+        return VerifyingKey::from_public_key_pem(key).map_err(|e| PacketReadingError::key("public_ed").with_source(e));
+    }
+
+    let raw: [u8; 32] = URL_SAFE.decode(key)
+        .map_err(|e| PacketReadingError::key("public_ed").with_source(e))?
+        .try_into()
+        .map_err(|_| PacketReadingError::key("public_ed"))?;
+    VerifyingKey::from_bytes(&raw).map_err(|e| PacketReadingError::key("public_ed").with_source(e))
+}
+
+/// Convert a PEM ed25519 public key, like the ones stored in the key files, to its compact form
+pub fn ed_public_pem_to_raw(pem: &str) -> Result<String, PacketReadingError> {
+    Ok(encode_ed_public(&decode_ed_public(pem)?))
+}
+
+/// Convert a compact ed25519 public key to its PKCS#8 PEM form
+pub fn ed_public_raw_to_pem(raw: &str) -> Result<String, PacketReadingError> {
+    decode_ed_public(raw)?.to_public_key_pem(Default::default()).map_err(|e| PacketReadingError::key("public_ed").with_source(e))
+}
+
+/// Compare two ed25519 public keys whatever their encoding. Values that are not keys are compared
+/// as strings.
+pub fn same_ed_key(a: &str, b: &str) -> bool {
+    match (decode_ed_public(a), decode_ed_public(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a.trim() == b.trim(),
+    }
+}
+
+/// Identifier of an ed25519 public key for the maps indexed by key: its compact form, so that both
+/// encodings of a key give the same entry
+pub fn ed_key_id(key: &str) -> String {
+    decode_ed_public(key).map(|key| encode_ed_public(&key)).unwrap_or_else(|_| key.trim().to_string())
+}
+
 
 #[derive(Debug)]
 pub enum SharedGenerationError {
     InvalidKeyError,
-    DecodeError
+    DecodeError,
+    /// Post-quantum KEM failure during an hybrid key agreement
+    KemError,
+    /// Hybrid key agreement negotiated but the KEM ciphertext is missing
+    Downgrade
+}
+
+impl std::fmt::Display for SharedGenerationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SharedGenerationError::InvalidKeyError => write!(f, "Invalid x25519 key"),
+            SharedGenerationError::DecodeError => write!(f, "Unable to decode x25519 key"),
+            SharedGenerationError::KemError => write!(f, "Post-quantum key agreement failed"),
+            SharedGenerationError::Downgrade => write!(f, "Missing post-quantum ciphertext"),
+        }
+    }
 }
+impl std::error::Error for SharedGenerationError {}
 
 // Important for the "?" to be usable when using URL_SAFE.decode
 impl From<DecodeError> for SharedGenerationError {
@@ -69,3 +137,23 @@ pub fn generate_shared_key(user_private: &str, target_public: &str) -> Result<St
 
     Ok(URL_SAFE.encode(shared))
 }
+
+#[cfg(test)]
+mod test {
+    use super::{decode_ed_public, ed_key_id, ed_public_pem_to_raw, ed_public_raw_to_pem, generate_ed_keys, same_ed_key};
+
+    #[test]
+    fn test_ed_public_encodings() {
+        let (_, pem) = generate_ed_keys();
+        let Ok(raw) = ed_public_pem_to_raw(&pem) else {
+            panic!("Unable to convert key");
+        };
+        assert_eq!(raw.len(), 44);
+        assert!(matches!(ed_public_raw_to_pem(&raw).as_deref(), Ok(converted) if converted == pem));
+
+        assert!(matches!((decode_ed_public(&pem), decode_ed_public(&raw)), (Ok(a), Ok(b)) if a == b));
+        assert!(same_ed_key(&pem, &raw));
+        assert_eq!(ed_key_id(&pem), raw);
+        assert!(decode_ed_public("not a key").is_err());
+    }
+}
@@ -1,13 +1,85 @@
 use std::{fmt};
 
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use chacha20poly1305::{aead::{Aead, AeadCore}, ChaCha20Poly1305, KeyInit, Nonce};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+
+use crate::{config::Padding, encryption::padding::{pad, unpad}, packets::{protocol::PADDING, PacketGenerationError, PacketReadingError}};
+
 pub mod keys;
 pub mod signature;
+pub mod rotation;
+pub mod revocation;
+pub mod safety;
+pub mod pinning;
+pub mod hybrid;
+pub mod deniable;
+pub mod sealed;
+pub mod pseudonym;
+pub mod padding;
+
+/// Size of the nonce put in front of every encrypted payload
+const NONCE_SIZE: usize = 12;
+
+/// Derive the ChaCha20-Poly1305 key from a base64 encoded 32 bytes key (shared or sender key)
+fn payload_cipher(shared_key: &str) -> Option<ChaCha20Poly1305> {
+    let decoded: [u8; 32] = URL_SAFE.decode(shared_key).ok()?.try_into().ok()?;
+    let derived = Sha256::new().chain_update(b"plume_payload").chain_update(decoded).finalize();
+
+    Some(ChaCha20Poly1305::new(&derived))
+}
 
 /// This function is used to encrypt the content of a message using the x25519 shared key
-pub fn encrypt_payload(_message: &str, _shared_key: &str) -> String {
-    todo!()
+///
+/// The result is the url safe base64 of the random nonce followed by the ciphertext
+pub fn encrypt_payload(message: &str, shared_key: &str) -> Result<String, PacketGenerationError> {
+    encrypt_bytes(message.as_bytes(), shared_key)
+}
+
+/// Decrypt a payload produced by [`encrypt_payload`] with the same shared key
+pub fn decrypt_payload(payload: &str, shared_key: &str) -> Result<String, PacketReadingError> {
+    String::from_utf8(decrypt_bytes(payload, shared_key)?).map_err(|e| PacketReadingError::data("payload").with_source(e))
+}
+
+/// Same as [`encrypt_payload`] but the message is padded first so that the ciphertext length only
+/// tells the padded length, see [`Padding`]
+pub fn encrypt_padded_payload(message: &str, shared_key: &str, padding: Padding) -> Result<String, PacketGenerationError> {
+    encrypt_bytes(&pad(message.as_bytes(), padding), shared_key)
+}
+
+/// Decrypt a payload produced by [`encrypt_padded_payload`], whatever the padding used, given the
+/// `capabilities` negotiated with its author. Authors without the "padding" capability don't pad
+/// their payloads, which are returned as they are.
+pub fn decrypt_padded_payload(payload: &str, shared_key: &str, capabilities: &[String]) -> Result<String, PacketReadingError> {
+    let mut message = decrypt_bytes(payload, shared_key)?;
+    if capabilities.iter().any(|capability| capability == PADDING) {
+        message = unpad(message)?;
+    }
+    String::from_utf8(message).map_err(|e| PacketReadingError::data("payload").with_source(e))
+}
+
+fn encrypt_bytes(message: &[u8], shared_key: &str) -> Result<String, PacketGenerationError> {
+    let cipher = payload_cipher(shared_key).ok_or_else(|| PacketGenerationError::shared_key("shared_key"))?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, message).map_err(|_| PacketGenerationError::shared_key("shared_key"))?;
+
+    let mut payload = nonce.to_vec();
+    payload.extend(ciphertext);
+    Ok(URL_SAFE.encode(payload))
 }
 
+fn decrypt_bytes(payload: &str, shared_key: &str) -> Result<Vec<u8>, PacketReadingError> {
+    let cipher = payload_cipher(shared_key).ok_or_else(|| PacketReadingError::key("shared_key"))?;
+    let decoded = URL_SAFE.decode(payload).map_err(|e| PacketReadingError::data("payload").with_source(e))?;
+    if decoded.len() < NONCE_SIZE {
+        return Err(PacketReadingError::data("payload"));
+    }
+
+    let (nonce, ciphertext) = decoded.split_at(NONCE_SIZE);
+    let nonce: [u8; NONCE_SIZE] = nonce.try_into().map_err(|e| PacketReadingError::data("payload").with_source(e))?;
+    cipher.decrypt(&Nonce::from(nonce), ciphertext).map_err(|_| PacketReadingError::key("shared_key"))
+}
 
 #[derive(Debug)]
 pub struct FormatError;
@@ -20,3 +92,37 @@ impl fmt::Display for FormatError {
 
 impl std::error::Error for FormatError {}
 
+#[cfg(test)]
+mod test {
+    use crate::encryption::keys::{generate_shared_key, generate_x_keys};
+
+    use crate::{config::Padding, packets::protocol::PADDING};
+
+    use super::{decrypt_padded_payload, decrypt_payload, encrypt_padded_payload, encrypt_payload};
+
+    #[test]
+    fn test_payload_round_trip() {
+        let (alice_private, alice_public) = generate_x_keys();
+        let (bob_private, bob_public) = generate_x_keys();
+        let alice_shared = generate_shared_key(&alice_private, &bob_public).expect("Unable to generate shared key");
+        let bob_shared = generate_shared_key(&bob_private, &alice_public).expect("Unable to generate shared key");
+
+        let payload = encrypt_payload("hello", &alice_shared).expect("Unable to encrypt payload");
+        assert!(matches!(decrypt_payload(&payload, &bob_shared).as_deref(), Ok("hello")));
+
+        let (_, other_public) = generate_x_keys();
+        let wrong_shared = generate_shared_key(&alice_private, &other_public).expect("Unable to generate shared key");
+        assert!(decrypt_payload(&payload, &wrong_shared).is_err());
+
+        // payloads of clients that don't pad their messages are read as they are, even when they end
+        // like a padding ("À" is C3 80)
+        let capabilities = [PADDING.to_string()];
+        assert!(matches!(decrypt_padded_payload(&payload, &bob_shared, &[]).as_deref(), Ok("hello")));
+        let legacy = encrypt_payload("À", &alice_shared).expect("Unable to encrypt payload");
+        assert!(matches!(decrypt_padded_payload(&legacy, &bob_shared, &[]).as_deref(), Ok("À")));
+        assert!(decrypt_padded_payload(&payload, &bob_shared, &capabilities).is_err());
+
+        let padded = encrypt_padded_payload("À", &alice_shared, Padding::Buckets).expect("Unable to encrypt payload");
+        assert!(matches!(decrypt_padded_payload(&padded, &bob_shared, &capabilities).as_deref(), Ok("À")));
+    }
+}
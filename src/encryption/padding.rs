@@ -0,0 +1,82 @@
+use crate::{config::Padding, packets::PacketReadingError};
+
+/// Byte marking the end of the message, followed by zeros up to the padded length (ISO/IEC 7816-4)
+const PADDING_MARKER: u8 = 0x80;
+
+/// Smallest bucket of [`Padding::Buckets`], each bucket is 4 times the previous one up to
+/// [`LARGEST_BUCKET`]
+const SMALLEST_BUCKET: usize = 256;
+
+/// Past this size, messages are padded to a multiple of it
+const LARGEST_BUCKET: usize = 64 * 1024;
+
+/// Length of a padded message of `length` bytes (marker included)
+pub fn padded_length(length: usize, padding: Padding) -> usize {
+    match padding {
+        Padding::None => length,
+        // Padmé: keep the exponent and the top bits of the length, the overhead is at most 12%
+        Padding::Padme => {
+            if length < 2 {
+                return length;
+            }
+            let exponent = length.ilog2();
+            let mantissa_bits = exponent - exponent.ilog2() - 1;
+            let mask = (1usize << mantissa_bits) - 1;
+            (length + mask) & !mask
+        }
+        Padding::Buckets => {
+            let mut bucket = SMALLEST_BUCKET;
+            while bucket < length && bucket < LARGEST_BUCKET {
+                bucket *= 4;
+            }
+            length.div_ceil(bucket).max(1) * bucket
+        }
+    }
+}
+
+/// Add the end marker to `message` then pad it with zeros following `padding`
+pub fn pad(message: &[u8], padding: Padding) -> Vec<u8> {
+    let mut padded = Vec::with_capacity(padded_length(message.len() + 1, padding));
+    padded.extend_from_slice(message);
+    padded.push(PADDING_MARKER);
+    padded.resize(padded_length(padded.len(), padding), 0);
+    padded
+}
+
+/// Remove the padding added by [`pad`], whatever the scheme used. Messages without a valid end
+/// marker are rejected.
+pub fn unpad(mut padded: Vec<u8>) -> Result<Vec<u8>, PacketReadingError> {
+    let end = padded.iter().rposition(|byte| *byte != 0).ok_or_else(|| PacketReadingError::data("padding"))?;
+    if padded[end] != PADDING_MARKER {
+        return Err(PacketReadingError::data("padding"));
+    }
+    padded.truncate(end);
+    Ok(padded)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Padding;
+
+    use super::{pad, padded_length, unpad};
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(padded_length(4, Padding::Buckets), 256);
+        assert_eq!(padded_length(300, Padding::Buckets), 1024);
+        assert_eq!(padded_length(70_000, Padding::Buckets), 131_072);
+        assert_eq!(padded_length(100, Padding::Padme), 104);
+        assert_eq!(padded_length(9, Padding::Padme), 10);
+
+        for padding in [Padding::None, Padding::Padme, Padding::Buckets] {
+            for message in [&b""[..], b"yes", &[0u8; 3], &[0x80; 700]] {
+                let padded = pad(message, padding);
+                assert_eq!(padded.len(), padded_length(message.len() + 1, padding));
+                assert!(matches!(unpad(padded).as_deref(), Ok(unpadded) if unpadded == message));
+            }
+        }
+
+        assert!(unpad(vec![b'y', b'e', b's', 0, 0]).is_err());
+        assert!(unpad(vec![0; 16]).is_err());
+    }
+}
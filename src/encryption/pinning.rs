@@ -0,0 +1,181 @@
+use std::fmt::Display;
+
+
+use crate::{config::{Friend, KeyKind, PinnedKey}, current_timestamp, devices::is_friend_key, encryption::keys::decode_ed_public, packets::Packet};
+
+/// Event raised when a friend presents a key that differs from the one pinned on first use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChanged {
+    pub username: String,
+    pub kind: KeyKind,
+    pub pinned: String,
+    pub presented: String,
+}
+
+impl Display for KeyChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            KeyKind::Identity => "identity",
+            KeyKind::Published => "published",
+        };
+        write!(f, "The {kind} key of {} changed, it must be accepted before sending anything to this friend", self.username)
+    }
+}
+impl std::error::Error for KeyChanged {}
+
+fn pinned_key(friend: &mut Friend, kind: KeyKind) -> &mut String {
+    match kind {
+        KeyKind::Identity => &mut friend.public_ed,
+        KeyKind::Published => &mut friend.public_published,
+    }
+}
+
+/// Identity keys are compared on their raw bytes so that two encodings of the same key match
+fn same_key(kind: KeyKind, pinned: &str, presented: &str) -> bool {
+    if kind == KeyKind::Identity
+        && let (Ok(pinned), Ok(presented)) = (decode_ed_public(pinned), decode_ed_public(presented)) {
+        return pinned == presented;
+    }
+    pinned.trim() == presented.trim()
+}
+
+/// Compare a key presented for `friend` with the pinned one. The key is pinned if none was known
+/// yet, otherwise a different key is stored as a pending change and a [`KeyChanged`] is returned.
+/// The key of one of the friend certified devices is not a change of its identity key, revoked
+/// devices are refused when verifying the packets they sign.
+pub fn check_friend_key(friend: &mut Friend, kind: KeyKind, presented: &str) -> Result<(), KeyChanged> {
+    if kind == KeyKind::Identity && !friend.public_ed.is_empty() && is_friend_key(friend, presented, &[]) {
+        return Ok(());
+    }
+    let pinned = pinned_key(friend, kind);
+
+    if pinned.is_empty() {
+        *pinned = presented.to_string();
+        return Ok(());
+    }
+    if same_key(kind, pinned, presented) {
+        return Ok(());
+    }
+
+    let pinned = pinned.clone();
+    let event = KeyChanged {
+        username: friend.username.clone(),
+        kind,
+        pinned,
+        presented: presented.to_string(),
+    };
+    friend.pending_key_change = Some(PinnedKey {
+        kind,
+        key: presented.to_string(),
+        changed_at: current_timestamp(),
+    });
+    Err(event)
+}
+
+/// Check the published key served by the relay for `friend` in a `RetrievePublished` answer. The
+/// signature of the packet must have been verified.
+pub fn check_retrieved_published(friend: &mut Friend, packet: &Packet) -> Result<(), KeyChanged> {
+    match packet {
+        Packet::RetrievePublished(data) if !data.key.is_empty() => check_friend_key(friend, KeyKind::Published, &data.key),
+        _ => Ok(()),
+    }
+}
+
+/// Refuse to send anything to a friend while a key change is waiting for the user approval
+pub fn ensure_can_send(friend: &Friend) -> Result<(), KeyChanged> {
+    match &friend.pending_key_change {
+        None => Ok(()),
+        Some(pending) => Err(KeyChanged {
+            username: friend.username.clone(),
+            kind: pending.kind,
+            pinned: match pending.kind {
+                KeyKind::Identity => friend.public_ed.clone(),
+                KeyKind::Published => friend.public_published.clone(),
+            },
+            presented: pending.key.clone(),
+        }),
+    }
+}
+
+/// Replace a pinned key and keep the previous one in the friend history
+pub fn replace_pinned_key(friend: &mut Friend, kind: KeyKind, key: &str) {
+    let previous = std::mem::replace(pinned_key(friend, kind), key.to_string());
+    if !previous.is_empty() {
+        friend.key_history.push(PinnedKey {
+            kind,
+            key: previous,
+            changed_at: current_timestamp(),
+        });
+    }
+}
+
+/// Accept the pending key change of a friend. The safety number changes with the identity key so
+/// the friend needs to be verified again.
+///
+/// Returns the accepted key, if any change was pending.
+pub fn accept_key_change(friend: &mut Friend) -> Option<PinnedKey> {
+    let pending = friend.pending_key_change.take()?;
+    replace_pinned_key(friend, pending.kind, &pending.key);
+    if pending.kind == KeyKind::Identity {
+        friend.verified = false;
+    }
+    Some(pending)
+}
+
+/// Drop the pending key change of a friend and keep the pinned key
+pub fn reject_key_change(friend: &mut Friend) -> Option<PinnedKey> {
+    friend.pending_key_change.take()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{config::{Friend, KeyKind}, devices::certify_device, encryption::keys::{generate_ed_keys, generate_x_keys}, packets::{Packet, RetrievePublishedData}};
+
+    use super::{accept_key_change, check_friend_key, check_retrieved_published, ensure_can_send};
+
+    #[test]
+    fn test_key_change_blocks_sending() {
+        let (_, first) = generate_ed_keys();
+        let (_, second) = generate_ed_keys();
+        let mut friend = Friend { username: String::from("friend"), verified: true, ..Default::default() };
+
+        assert!(check_friend_key(&mut friend, KeyKind::Identity, &first).is_ok());
+        assert!(check_friend_key(&mut friend, KeyKind::Identity, &first).is_ok());
+        assert!(ensure_can_send(&friend).is_ok());
+
+        let event = check_friend_key(&mut friend, KeyKind::Identity, &second).expect_err("Key change not detected");
+        assert_eq!(event.presented, second);
+        assert!(ensure_can_send(&friend).is_err());
+
+        accept_key_change(&mut friend).expect("No pending key change");
+        assert!(ensure_can_send(&friend).is_ok());
+        assert_eq!(friend.public_ed, second);
+        assert_eq!(friend.key_history[0].key, first);
+        assert!(!friend.verified);
+    }
+
+    #[test]
+    fn test_device_key_is_not_a_key_change() {
+        let (identity_private, identity_public) = generate_ed_keys();
+        let (_, device_ed) = generate_ed_keys();
+        let (_, device_published) = generate_x_keys();
+        let certificate = certify_device(&identity_private, &identity_public, "phone", &device_ed, &device_published).expect("Unable to certify device");
+        let mut friend = Friend { public_ed: identity_public, devices: vec![certificate], ..Default::default() };
+
+        assert!(check_friend_key(&mut friend, KeyKind::Identity, &device_ed).is_ok());
+        assert!(friend.pending_key_change.is_none());
+    }
+
+    #[test]
+    fn test_retrieved_published_key_change() {
+        let (_, first) = generate_x_keys();
+        let (_, second) = generate_x_keys();
+        let mut friend = Friend { username: String::from("friend"), public_published: first.clone(), ..Default::default() };
+        let retrieved = |key: &str| Packet::RetrievePublished(RetrievePublishedData { key: key.to_string(), ..Default::default() });
+
+        assert!(check_retrieved_published(&mut friend, &retrieved(&first)).is_ok());
+        assert!(check_retrieved_published(&mut friend, &retrieved(&second)).is_err());
+        assert!(ensure_can_send(&friend).is_err());
+        assert_eq!(friend.public_published, first);
+    }
+}
@@ -0,0 +1,244 @@
+use std::{fs, str::FromStr};
+
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use ed25519_dalek::{Signature as EdSignature, Signer, SigningKey, pkcs8::{DecodePrivateKey, EncodePrivateKey}};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{config::{Config, KeyKind}, current_timestamp, encryption::{decrypt_payload, encrypt_payload, keys::{decode_ed_public, encode_ed_public, same_ed_key}, pinning::check_friend_key, signature::{compact_author_key, sign_packet, verify_packet}}, packets::{protocol::local_capabilities, LoginData, Packet, PacketGenerationError, PacketHeader, PacketReadingError, PseudonymData, RegisterData}};
+
+/// Certificate binding the login key used on a relay to the identity key. It is only ever sent to
+/// friends, encrypted, relays only see `login_key`.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct RelayPseudonym {
+    pub relay: String,
+    pub identity_key: String,
+    pub login_key: String,
+    pub created_at: u64,
+    pub signature: String,
+}
+
+impl RelayPseudonym {
+    pub fn get_signature_payload(&self) -> String {
+        format!("pseudonym{}{}{}{}", self.identity_key, self.relay, self.login_key, self.created_at)
+    }
+}
+
+/// Derive the login keypair of `relay` from the identity key. The derivation is deterministic so the
+/// private key doesn't need to be stored, and without the identity private key the login keys of two
+/// relays can't be linked together.
+///
+/// returns !
+///
+/// (private: String, public: String), the private key in its PKCS#8 PEM form and the public key in
+/// its compact form, see [`encode_ed_public`]
+pub fn derive_relay_keys(identity_private: &str, relay: &str) -> Result<(String, String), PacketGenerationError> {
+    let identity = SigningKey::from_pkcs8_pem(identity_private)?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(identity.as_bytes()).map_err(|e| PacketGenerationError::signing_key("identity_private").with_source(e))?;
+    mac.update(b"plume_relay_login");
+    mac.update(relay.as_bytes());
+    let seed: [u8; 32] = mac.finalize().into_bytes().into();
+
+    let login = SigningKey::from_bytes(&seed);
+    let private = login.to_pkcs8_pem(Default::default())?.to_string();
+    Ok((private, encode_ed_public(&login.verifying_key())))
+}
+
+/// Derive the published x25519 keypair given to `relay` from our published private key, the same
+/// way as [`derive_relay_keys`], so that relays can't link accounts by comparing published keys.
+/// Friend requests received through `relay` are answered with the private key derived here.
+///
+/// returns !
+///
+/// (base64_private: String, base64_public: String)
+pub fn derive_relay_published(published_private: &str, relay: &str) -> Result<(String, String), PacketGenerationError> {
+    let published: [u8; 32] = URL_SAFE.decode(published_private)
+        .map_err(|e| PacketGenerationError::shared_key("published_private").with_source(e))?
+        .try_into()
+        .map_err(|_| PacketGenerationError::shared_key("published_private"))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&published).map_err(|e| PacketGenerationError::shared_key("published_private").with_source(e))?;
+    mac.update(b"plume_relay_published");
+    mac.update(relay.as_bytes());
+    let seed: [u8; 32] = mac.finalize().into_bytes().into();
+
+    let secret = x25519_dalek::StaticSecret::from(seed);
+    let public = x25519_dalek::PublicKey::from(&secret);
+    Ok((URL_SAFE.encode(secret), URL_SAFE.encode(public)))
+}
+
+/// Get the login keys of `relay`, certifying them the first time the relay is used.
+/// The caller is responsible for writing the configuration.
+///
+/// returns !
+///
+/// (private: String, certificate: RelayPseudonym)
+pub fn relay_pseudonym(config: &mut Config, relay: &str) -> Result<(String, RelayPseudonym), PacketGenerationError> {
+    let public_ed = fs::read_to_string(&config.me.public_ed_path).map_err(|e| PacketGenerationError::ed_key("me.public_ed_path").with_source(e))?;
+    let private_ed = fs::read_to_string(&config.me.private_ed_path).map_err(|e| PacketGenerationError::signing_key("me.private_ed_path").with_source(e))?;
+    let (private_login, public_login) = derive_relay_keys(&private_ed, relay)?;
+
+    if let Some(pseudonym) = config.me.pseudonyms.get(relay) && pseudonym.login_key == public_login {
+        return Ok((private_login, pseudonym.clone()));
+    }
+
+    let identity = SigningKey::from_pkcs8_pem(&private_ed)?;
+    let mut pseudonym = RelayPseudonym {
+        relay: relay.to_string(),
+        identity_key: public_ed,
+        login_key: public_login,
+        created_at: current_timestamp(),
+        signature: String::default(),
+    };
+    pseudonym.signature = identity.sign(pseudonym.get_signature_payload().as_bytes()).to_string();
+
+    config.me.pseudonyms.insert(relay.to_string(), pseudonym.clone());
+    Ok((private_login, pseudonym))
+}
+
+/// Verify that a pseudonym has been certified by `identity_key`
+pub fn verify_relay_pseudonym(pseudonym: &RelayPseudonym, identity_key: &str) -> Result<(), PacketReadingError> {
+    let identity = decode_ed_public(identity_key).map_err(|e| e.with_field("identity_key"))?;
+    if decode_ed_public(&pseudonym.identity_key).map_err(|e| e.with_field("pseudonym.identity_key"))? != identity {
+        return Err(PacketReadingError::key("pseudonym.identity_key"));
+    }
+
+    let signature = EdSignature::from_str(&pseudonym.signature)?;
+    identity.verify_strict(pseudonym.get_signature_payload().as_bytes(), &signature)?;
+    Ok(())
+}
+
+/// Login packet for `relay`, signed with its login key instead of the identity key
+pub fn login_packet(config: &mut Config, relay: &str) -> Result<Packet, PacketGenerationError> {
+    let (private_login, pseudonym) = relay_pseudonym(config, relay)?;
+
+    let mut packet = Packet::Login(LoginData {
+        headers: PacketHeader {
+            action: String::from("login"),
+            author_key: pseudonym.login_key,
+            signature: String::default(),
+            ..Default::default()
+        },
+        capabilities: local_capabilities(&config.me),
+    });
+    sign_packet(&mut packet, &private_login)?;
+    Ok(packet)
+}
+
+/// Register packet for `relay`, signed with its login key instead of the identity key. The
+/// published key is the one derived for this relay, see [`derive_relay_published`], along with the
+/// published KEM key when we have one.
+pub fn register_packet(config: &mut Config, relay: &str) -> Result<Packet, PacketGenerationError> {
+    let (private_login, pseudonym) = relay_pseudonym(config, relay)?;
+    let private_published = fs::read_to_string(&config.me.private_published_path).map_err(|e| PacketGenerationError::shared_key("me.private_published_path").with_source(e))?;
+    let (_, public_published) = derive_relay_published(&private_published, relay)?;
+    let kem_published = if config.me.public_kem_published_path.is_empty() {
+        String::new()
+    } else {
+        fs::read_to_string(&config.me.public_kem_published_path).map_err(|e| PacketGenerationError::shared_key("me.public_kem_published_path").with_source(e))?
+    };
+
+    let mut packet = Packet::Register(RegisterData {
+        headers: PacketHeader {
+            action: String::from("register"),
+            author_key: pseudonym.login_key,
+            signature: String::default(),
+            ..Default::default()
+        },
+        author_published: public_published,
+        author_kem_published: kem_published,
+    });
+    sign_packet(&mut packet, &private_login)?;
+    Ok(packet)
+}
+
+/// Packets giving every relay pseudonym to every friend, signed with the identity key
+pub fn share_pseudonyms(config: &Config) -> Result<Vec<Packet>, PacketGenerationError> {
+    let public_ed = fs::read_to_string(&config.me.public_ed_path).map_err(|e| PacketGenerationError::ed_key("me.public_ed_path").with_source(e))?;
+    let private_ed = fs::read_to_string(&config.me.private_ed_path).map_err(|e| PacketGenerationError::signing_key("me.private_ed_path").with_source(e))?;
+
+    let mut packets = Vec::with_capacity(config.friends.len() * config.me.pseudonyms.len());
+    for friend in config.friends.values() {
+        for pseudonym in config.me.pseudonyms.values() {
+            let mut headers = PacketHeader {
+                action: String::from("pseudonym"),
+                author_key: public_ed.clone(),
+                signature: String::default(),
+                ..Default::default()
+            };
+            compact_author_key(&mut headers, &friend.capabilities);
+            let mut packet = Packet::Pseudonym(PseudonymData {
+                headers,
+                recipient: friend.public_ed.clone(),
+                certificate: encrypt_payload(&serde_json::to_string(pseudonym)?, &friend.shared_key)?,
+            });
+            sign_packet(&mut packet, &private_ed)?;
+            packets.push(packet);
+        }
+    }
+    Ok(packets)
+}
+
+/// Store the pseudonym received from a friend once its certificate is verified.
+/// The caller is responsible for writing the configuration.
+pub fn store_friend_pseudonym(config: &mut Config, packet: &Packet) -> Result<(), PacketReadingError> {
+    let Packet::Pseudonym(data) = packet else {
+        return Err(PacketReadingError::unexpected(packet));
+    };
+    verify_packet(config, packet)?;
+
+    let friend = config.friends.values_mut()
+        .find(|friend| same_ed_key(&friend.public_ed, &data.headers.author_key))
+        .ok_or_else(|| PacketReadingError::key("headers.author_key").with_action(&data.headers.action))?;
+    let pseudonym: RelayPseudonym = serde_json::from_str(&decrypt_payload(&data.certificate, &friend.shared_key)?)?;
+    check_friend_key(friend, KeyKind::Identity, &pseudonym.identity_key)
+        .map_err(|e| PacketReadingError::key("certificate.identity_key").with_action(&data.headers.action).with_source(e))?;
+    verify_relay_pseudonym(&pseudonym, &friend.public_ed)?;
+
+    friend.pseudonyms.insert(pseudonym.relay, pseudonym.login_key);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encryption::keys::{ed_key_id, generate_ed_keys, generate_shared_key, generate_x_keys};
+
+    use super::{derive_relay_keys, derive_relay_published};
+
+    #[test]
+    fn test_relay_keys_are_unlinkable() {
+        let (identity_private, identity_public) = generate_ed_keys();
+
+        let (_, first) = derive_relay_keys(&identity_private, "relay.one").expect("Unable to derive relay keys");
+        let (_, again) = derive_relay_keys(&identity_private, "relay.one").expect("Unable to derive relay keys");
+        let (_, second) = derive_relay_keys(&identity_private, "relay.two").expect("Unable to derive relay keys");
+
+        assert_eq!(first, again);
+        assert_ne!(first, second);
+        assert_ne!(first, identity_public);
+        // login keys are only read by relays, they are always compact
+        assert_eq!(ed_key_id(&first), first);
+    }
+
+    #[test]
+    fn test_relay_published_keys_are_unlinkable() {
+        let (published_private, published_public) = generate_x_keys();
+
+        let (first_private, first) = derive_relay_published(&published_private, "relay.one").expect("Unable to derive published keys");
+        let (_, again) = derive_relay_published(&published_private, "relay.one").expect("Unable to derive published keys");
+        let (_, second) = derive_relay_published(&published_private, "relay.two").expect("Unable to derive published keys");
+
+        assert_eq!(first, again);
+        assert_ne!(first, second);
+        assert_ne!(first, published_public);
+
+        // a friend request sent to the key given to the relay can be answered with the derived private key
+        let (author_private, author_public) = generate_x_keys();
+        assert_eq!(
+            generate_shared_key(&author_private, &first).expect("Unable to generate shared key"),
+            generate_shared_key(&first_private, &author_public).expect("Unable to generate shared key"),
+        );
+    }
+}
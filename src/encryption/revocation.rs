@@ -0,0 +1,110 @@
+use std::str::FromStr;
+
+use ed25519_dalek::{Signature as EdSignature, Signer, SigningKey, VerifyingKey, pkcs8::DecodePrivateKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, current_timestamp, encryption::keys::{decode_ed_public, encode_ed_public, same_ed_key}, packets::{Packet, PacketGenerationError, PacketReadingError}};
+
+/// Statement signed by an identity key declaring that this key must not be trusted anymore.
+/// It is generated ahead of time (see [`crate::init`]) and stored offline so that it can be
+/// published even after the private key has been lost or stolen.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct RevocationCertificate {
+    pub revoked_key: String,
+    pub issued_at: u64,
+    pub reason: String,
+    pub signature: String,
+}
+
+impl RevocationCertificate {
+    pub fn get_signature_payload(&self) -> String {
+        format!("revoke{}{}{}", self.revoked_key, self.issued_at, self.reason)
+    }
+}
+
+/// Generate a revocation certificate for the identity key matching `private_ed`, written in its
+/// compact form since the certificate is published to relays and friends alike
+pub fn generate_revocation_certificate(private_ed: &str, public_ed: &str, reason: &str) -> Result<RevocationCertificate, PacketGenerationError> {
+    let key = SigningKey::from_pkcs8_pem(private_ed)?;
+    let verifying = decode_ed_public(public_ed).map_err(|e| PacketGenerationError::ed_key("public_ed").with_source(e))?;
+    if key.verifying_key() != verifying {
+        return Err(PacketGenerationError::ed_key("public_ed"));
+    }
+
+    let mut certificate = RevocationCertificate {
+        revoked_key: encode_ed_public(&verifying),
+        issued_at: current_timestamp(),
+        reason: reason.to_string(),
+        signature: String::default(),
+    };
+    certificate.signature = key.sign(certificate.get_signature_payload().as_bytes()).to_string();
+
+    Ok(certificate)
+}
+
+/// Verify that a certificate has been signed by the key it revokes
+pub fn verify_revocation_certificate(certificate: &RevocationCertificate) -> Result<VerifyingKey, PacketReadingError> {
+    let key = decode_ed_public(&certificate.revoked_key).map_err(|e| e.with_field("certificate.revoked_key"))?;
+    let signature = EdSignature::from_str(&certificate.signature)?;
+    key.verify_strict(certificate.get_signature_payload().as_bytes(), &signature)?;
+    Ok(key)
+}
+
+/// Check whether a key is among `revoked_keys` (the revoked keys of a configuration), keys are
+/// compared on their raw bytes so that their encoding doesn't matter
+pub fn is_revoked(revoked_keys: &[String], key: &VerifyingKey) -> bool {
+    revoked_keys.iter().any(|revoked| decode_ed_public(revoked).is_ok_and(|revoked| revoked == *key))
+}
+
+/// Handle a received `Revoke` packet: the certificate is verified, then the key is added to the
+/// revoked keys of the configuration, refused from then on by
+/// [`crate::encryption::signature::verify_packet`]. The caller is responsible for writing the
+/// configuration.
+pub fn apply_revocation(config: &mut Config, packet: &Packet) -> Result<(), PacketReadingError> {
+    let Packet::Revoke(data) = packet else {
+        return Err(PacketReadingError::unexpected(packet));
+    };
+
+    verify_revocation_certificate(&data.certificate)?;
+    if !config.revoked_keys.iter().any(|revoked| same_ed_key(revoked, &data.certificate.revoked_key)) {
+        config.revoked_keys.push(data.certificate.revoked_key.clone());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{config::Config, encryption::{keys::generate_ed_keys, signature::{sign_packet, verify_packet}}, packets::{LoginData, Packet, PacketHeader, PacketReadingError, RevokeData}};
+
+    use super::{apply_revocation, generate_revocation_certificate, verify_revocation_certificate};
+
+    #[test]
+    fn test_revoked_author_refused() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let mut certificate = generate_revocation_certificate(&private_ed, &public_ed, "compromised").expect("Unable to generate certificate");
+        assert!(verify_revocation_certificate(&certificate).is_ok());
+
+        let mut packet = Packet::Login(LoginData {
+            headers: PacketHeader {
+                action: String::from("login"),
+                author_key: public_ed,
+                signature: String::default(),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        sign_packet(&mut packet, &private_ed).expect("Unable to sign packet");
+        let mut config = Config::default();
+        assert!(verify_packet(&config, &packet).is_ok());
+
+        let revoke = Packet::Revoke(RevokeData { certificate: certificate.clone(), ..Default::default() });
+        apply_revocation(&mut config, &revoke).expect("Valid revocation refused");
+        assert!(matches!(verify_packet(&config, &packet), Err(PacketReadingError::Revoked { .. })));
+
+        // revocations only apply to the configuration they have been stored in
+        assert!(verify_packet(&Config::default(), &packet).is_ok());
+
+        certificate.reason = String::from("tampered");
+        assert!(verify_revocation_certificate(&certificate).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::{fmt::Display, fs, path::Path};
+
+use crate::{config::{update_config, Config, Friend, KeyKind, Me, PreviousPublished}, current_timestamp, encryption::{keys::{generate_x_keys, same_ed_key}, pinning::{check_friend_key, replace_pinned_key, KeyChanged}, pseudonym::register_packet, signature::{compact_author_key, sign_packet, verify_packet_signature}}, packets::{Packet, PacketGenerationError, PacketHeader, PublishedRotationData}};
+
+/// Default time during which a rotated published key is still accepted: one week
+pub const DEFAULT_GRACE_PERIOD: u64 = 60 * 60 * 24 * 7;
+
+#[derive(Debug)]
+pub enum RotationError {
+    Io(std::io::Error),
+    Generation(PacketGenerationError),
+    WrongPacket,
+    UnknownAuthor,
+    InvalidSignature,
+    /// Notice replacing another published key than the pinned one, kept as a pending key change
+    KeyChanged(KeyChanged),
+}
+
+impl Display for RotationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RotationError::Io(e) => {
+                write!(f, "IO error: {}", e)
+            }
+            RotationError::Generation(e) => {
+                write!(f, "Unable to generate rotation packets: {}", e)
+            }
+            RotationError::WrongPacket => {
+                write!(f, "Packet is not a published key rotation notice")
+            }
+            RotationError::UnknownAuthor => {
+                write!(f, "Rotation notice was not signed by this friend")
+            }
+            RotationError::InvalidSignature => {
+                write!(f, "Rotation notice has an invalid signature")
+            }
+            RotationError::KeyChanged(e) => {
+                write!(f, "Rotation notice does not replace the currently known published key: {}", e)
+            }
+        }
+    }
+}
+impl std::error::Error for RotationError {}
+
+/// Generate a new published x25519 key and move the current one in the grace list of `@me`.
+/// The configuration is written back to disk once the new keys are stored.
+///
+/// returns the signed packets to send, in this order :
+///
+/// (registers: Vec<Packet>, notices: Vec<Packet>)
+///
+/// `registers` holds one packet per relay we have a pseudonym on, to send to that relay so it
+/// serves the newly derived published key, and each packet of `notices` must be sent to the friend
+/// designated by its recipient.
+pub fn rotate_published_key(config: &mut Config, grace_period: u64) -> Result<(Vec<Packet>, Vec<Packet>), RotationError> {
+    let public_ed = fs::read_to_string(&config.me.public_ed_path)?;
+    let private_ed = fs::read_to_string(&config.me.private_ed_path)?;
+    let old_public = fs::read_to_string(&config.me.public_published_path)?;
+    let old_private = fs::read_to_string(&config.me.private_published_path)?;
+
+    let now = current_timestamp();
+    let grace_until = now + grace_period;
+
+    // keep the old private key aside so packets sent to it can still be read during the grace period
+    let keys_dir = Path::new(&config.me.private_published_path).parent().unwrap_or(Path::new("."));
+    let archived_path = keys_dir.join(format!("private_published_{now}.pem")).to_string_lossy().to_string();
+    fs::write(&archived_path, old_private)?;
+
+    let (private_published, public_published) = generate_x_keys();
+    fs::write(&config.me.public_published_path, &public_published)?;
+    fs::write(&config.me.private_published_path, private_published)?;
+
+    config.me.previous_published.push(PreviousPublished {
+        public_published: old_public.clone(),
+        private_published_path: archived_path,
+        expires_at: grace_until,
+    });
+    // every relay gets the published key derived for it, under our pseudonym on that relay
+    let relays: Vec<String> = config.me.pseudonyms.keys().cloned().collect();
+    let mut registers = Vec::with_capacity(relays.len());
+    for relay in relays {
+        registers.push(register_packet(config, &relay)?);
+    }
+    update_config(config);
+
+    let mut notices = Vec::with_capacity(config.friends.len());
+    for friend in config.friends.values() {
+        let mut headers = PacketHeader {
+            action: String::from("published_rotation"),
+            author_key: public_ed.clone(),
+            signature: String::default(),
+            ..Default::default()
+        };
+        compact_author_key(&mut headers, &friend.capabilities);
+        let mut notice = Packet::PublishedRotation(PublishedRotationData {
+            headers,
+            recipient: friend.public_ed.clone(),
+            old_published: old_public.clone(),
+            new_published: public_published.clone(),
+            grace_until,
+        });
+        sign_packet(&mut notice, &private_ed)?;
+        notices.push(notice);
+    }
+
+    Ok((registers, notices))
+}
+
+/// Verify a rotation notice received from `friend` and update its published key.
+/// The notice must be signed by the friend identity key and replace the published key currently
+/// stored for this friend (if one is known). The replaced key is kept in the friend key history.
+/// A notice replacing another key is handled as a key change, see [`check_friend_key`]. Notices
+/// written by one of `revoked_keys` are refused.
+pub fn apply_published_rotation(friend: &mut Friend, packet: &Packet, revoked_keys: &[String]) -> Result<(), RotationError> {
+    let Packet::PublishedRotation(notice) = packet else {
+        return Err(RotationError::WrongPacket);
+    };
+
+    if !same_ed_key(&notice.headers.author_key, &friend.public_ed) {
+        return Err(RotationError::UnknownAuthor);
+    }
+    verify_packet_signature(packet, revoked_keys).map_err(|_| RotationError::InvalidSignature)?;
+
+    if !friend.public_published.is_empty() && friend.public_published != notice.old_published {
+        check_friend_key(friend, KeyKind::Published, &notice.new_published)?;
+    }
+
+    replace_pinned_key(friend, KeyKind::Published, &notice.new_published);
+    Ok(())
+}
+
+/// Private published keys able to open sealed messages and mailbox deliveries at `now`: the
+/// current one first, then the rotated ones whose grace period hasn't ended. This is the list to
+/// give to [`crate::encryption::sealed::unseal_payload`].
+pub fn private_published_keys(me: &Me, now: u64) -> Result<Vec<String>, RotationError> {
+    let mut keys = vec![fs::read_to_string(&me.private_published_path)?];
+    for key in me.previous_published.iter().filter(|key| key.expires_at > now) {
+        keys.push(fs::read_to_string(&key.private_published_path)?);
+    }
+    Ok(keys)
+}
+
+/// Remove the rotated published keys whose grace period ended before `now`, along with their
+/// private key file
+pub fn prune_expired_published(me: &mut Me, now: u64) -> Result<(), RotationError> {
+    let (expired, kept): (Vec<PreviousPublished>, Vec<PreviousPublished>) = me.previous_published.drain(..).partition(|key| key.expires_at <= now);
+    me.previous_published = kept;
+
+    for key in expired {
+        if fs::exists(&key.private_published_path)? {
+            fs::remove_file(&key.private_published_path)?;
+        }
+    }
+    Ok(())
+}
+
+impl From<std::io::Error> for RotationError {
+    fn from(err: std::io::Error) -> Self {
+        RotationError::Io(err)
+    }
+}
+
+impl From<KeyChanged> for RotationError {
+    fn from(err: KeyChanged) -> Self {
+        RotationError::KeyChanged(err)
+    }
+}
+
+impl From<PacketGenerationError> for RotationError {
+    fn from(err: PacketGenerationError) -> Self {
+        RotationError::Generation(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, path::Path};
+
+    use crate::{config::{Friend, Me, PreviousPublished}, encryption::{keys::{generate_ed_keys, generate_x_keys}, pseudonym::{derive_relay_published, relay_pseudonym}, signature::{sign_packet, verify_packet_signature}}, packets::{Packet, PacketHeader, PublishedRotationData}, test::TestUser};
+
+    use super::{apply_published_rotation, private_published_keys, rotate_published_key, RotationError, DEFAULT_GRACE_PERIOD};
+
+    fn friend(public_ed: &str) -> Friend {
+        Friend {
+            public_ed: public_ed.to_string(),
+            username: String::from("friend"),
+            public_published: String::from("old"),
+            ..Default::default()
+        }
+    }
+
+    fn notice(author: &str, private: &str, old: &str) -> Packet {
+        let mut packet = Packet::PublishedRotation(PublishedRotationData {
+            headers: PacketHeader {
+                action: String::from("published_rotation"),
+                author_key: author.to_string(),
+                signature: String::default(),
+                ..Default::default()
+            },
+            recipient: String::from("me"),
+            old_published: old.to_string(),
+            new_published: String::from("new"),
+            grace_until: 0,
+        });
+        sign_packet(&mut packet, private).expect("Unable to sign notice");
+        packet
+    }
+
+    #[test]
+    fn test_apply_published_rotation() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let mut friend = friend(&public_ed);
+
+        assert!(matches!(apply_published_rotation(&mut friend, &notice(&public_ed, &private_ed, "other"), &[]), Err(RotationError::KeyChanged(_))));
+        assert!(friend.pending_key_change.take().is_some());
+
+        let (other_private, other_public) = generate_ed_keys();
+        assert!(matches!(apply_published_rotation(&mut friend, &notice(&other_public, &other_private, "old"), &[]), Err(RotationError::UnknownAuthor)));
+
+        // notices of a revoked identity are refused
+        let revoked = [public_ed.clone()];
+        assert!(matches!(apply_published_rotation(&mut friend, &notice(&public_ed, &private_ed, "old"), &revoked), Err(RotationError::InvalidSignature)));
+
+        apply_published_rotation(&mut friend, &notice(&public_ed, &private_ed, "old"), &[]).expect("Valid notice refused");
+        assert_eq!(friend.public_published, "new");
+    }
+
+    #[test]
+    fn test_grace_period_enforced() {
+        let dir = env::temp_dir().join(format!("plume_rotation_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).expect("Unable to create key directory");
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        fs::write(path("current.pem"), "current").expect("Unable to write key");
+        fs::write(path("recent.pem"), "recent").expect("Unable to write key");
+        fs::write(path("expired.pem"), "expired").expect("Unable to write key");
+
+        let previous = |name: &str, expires_at: u64| PreviousPublished {
+            public_published: String::default(),
+            private_published_path: path(name),
+            expires_at,
+        };
+        let me = Me {
+            private_published_path: path("current.pem"),
+            previous_published: vec![previous("expired.pem", 100), previous("recent.pem", 300)],
+            ..Default::default()
+        };
+
+        let keys = private_published_keys(&me, 200).expect("Unable to read published keys");
+        assert_eq!(keys, vec![String::from("current"), String::from("recent")]);
+        assert_eq!(private_published_keys(&me, 300).expect("Unable to read published keys"), vec![String::from("current")]);
+
+        fs::remove_dir_all(&dir).expect("Unable to delete key directory");
+    }
+
+    #[test]
+    fn test_rotation_registers_on_each_relay() {
+        let _guard = crate::test::config_folder();
+        let mut user = TestUser::new("rotation");
+        let dir = Path::new(&user.config.me.private_ed_path).parent().expect("No key directory").to_path_buf();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let (private_published, public_published) = generate_x_keys();
+        fs::write(path("private_published.pem"), private_published).expect("Unable to write key");
+        fs::write(path("public_published.pem"), public_published).expect("Unable to write key");
+        fs::write(path("public_kem_published.pem"), "kem").expect("Unable to write key");
+        user.config.me.private_published_path = path("private_published.pem");
+        user.config.me.public_published_path = path("public_published.pem");
+        user.config.me.public_kem_published_path = path("public_kem_published.pem");
+        for relay in ["relay.one", "relay.two"] {
+            relay_pseudonym(&mut user.config, relay).expect("Unable to certify pseudonym");
+        }
+
+        let (registers, _) = rotate_published_key(&mut user.config, DEFAULT_GRACE_PERIOD).expect("Unable to rotate key");
+        assert_eq!(registers.len(), 2);
+        let private_published = fs::read_to_string(path("private_published.pem")).expect("Unable to read key");
+        for register in &registers {
+            let Packet::Register(data) = register else {
+                panic!("Not a register packet");
+            };
+            // relays only see the pseudonym and the key derived for them, never the identity or raw published key
+            let relay = user.config.me.pseudonyms.values().find(|pseudonym| pseudonym.login_key == data.headers.author_key).expect("Not written under a pseudonym").relay.clone();
+            assert_eq!(data.author_published, derive_relay_published(&private_published, &relay).expect("Unable to derive key").1);
+            assert_eq!(data.author_kem_published, "kem");
+            verify_packet_signature(register, &[]).expect("Invalid register signature");
+        }
+    }
+}
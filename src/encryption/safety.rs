@@ -0,0 +1,110 @@
+use std::fmt::Display;
+
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha512};
+
+use crate::{config::Friend, encryption::keys::decode_ed_public, packets::PacketReadingError};
+
+/// Version of the safety number format, changing it changes every safety number
+const SAFETY_NUMBER_VERSION: u8 = 0;
+/// Number of hash iterations used to derive the fingerprint of a key
+const FINGERPRINT_ITERATIONS: usize = 5200;
+/// Number of 5 digits groups generated for each key
+const GROUPS_PER_KEY: usize = 6;
+
+/// Fingerprint of a conversation between two users, identical on both sides.
+/// `digits` are meant to be read out loud, `payload` to be encoded in a QR code and scanned by
+/// the other user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    pub digits: String,
+    pub payload: String,
+}
+
+impl SafetyNumber {
+    /// Digits split in groups of 5
+    pub fn groups(&self) -> Vec<&str> {
+        (0..self.digits.len()).step_by(5).map(|i| &self.digits[i..i + 5]).collect()
+    }
+}
+
+impl Display for SafetyNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.groups().join(" "))
+    }
+}
+
+/// Iterated hash of a public key, truncated to the bytes needed for its digit groups
+fn fingerprint(key: &VerifyingKey) -> [u8; GROUPS_PER_KEY * 5] {
+    let key = key.to_bytes();
+    let mut hash = Sha512::new()
+        .chain_update([SAFETY_NUMBER_VERSION])
+        .chain_update(key)
+        .finalize();
+
+    for _ in 1..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new().chain_update(hash).chain_update(key).finalize();
+    }
+
+    let mut truncated = [0u8; GROUPS_PER_KEY * 5];
+    truncated.copy_from_slice(&hash[..GROUPS_PER_KEY * 5]);
+    truncated
+}
+
+fn fingerprint_digits(fingerprint: &[u8]) -> String {
+    fingerprint.chunks(5).map(|chunk| {
+        let value = chunk.iter().fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+        format!("{:05}", value % 100_000)
+    }).collect()
+}
+
+/// Compute the safety number of the conversation between two ed25519 public keys (PEM format).
+/// The result does not depend on the order of the keys, so both friends get the same one.
+pub fn compute_safety_number(local_ed: &str, remote_ed: &str) -> Result<SafetyNumber, PacketReadingError> {
+    let mut fingerprints = [
+        fingerprint(&decode_ed_public(local_ed).map_err(|e| e.with_field("local_ed"))?),
+        fingerprint(&decode_ed_public(remote_ed).map_err(|e| e.with_field("remote_ed"))?),
+    ];
+    fingerprints.sort();
+
+    let digits = fingerprints.iter().map(|f| fingerprint_digits(f)).collect();
+
+    let mut payload = vec![SAFETY_NUMBER_VERSION];
+    payload.extend(fingerprints.concat());
+
+    Ok(SafetyNumber { digits, payload: URL_SAFE.encode(payload) })
+}
+
+/// Compare the payload scanned from the friend device with our own safety number and mark the
+/// friend as verified when they match.
+///
+/// Returns whether the payloads matched. The caller is responsible for writing the configuration.
+pub fn verify_friend(local_ed: &str, friend: &mut Friend, scanned_payload: &str) -> Result<bool, PacketReadingError> {
+    let safety_number = compute_safety_number(local_ed, &friend.public_ed)?;
+    friend.verified = safety_number.payload == scanned_payload.trim();
+    Ok(friend.verified)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encryption::keys::generate_ed_keys;
+
+    use super::compute_safety_number;
+
+    #[test]
+    fn test_safety_number_symmetric() {
+        let (_, alice) = generate_ed_keys();
+        let (_, bob) = generate_ed_keys();
+        let (_, mallory) = generate_ed_keys();
+
+        let (Ok(alice_side), Ok(bob_side), Ok(mitm)) = (compute_safety_number(&alice, &bob), compute_safety_number(&bob, &alice), compute_safety_number(&alice, &mallory)) else {
+            panic!("Unable to compute safety numbers");
+        };
+
+        assert_eq!(alice_side, bob_side);
+        assert_ne!(alice_side, mitm);
+        assert_eq!(alice_side.digits.len(), 60);
+        assert_eq!(alice_side.groups().len(), 12);
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs;
+
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{config::Config, encryption::{decrypt_payload, encrypt_payload, keys::{ed_public_pem_to_raw, generate_shared_key, generate_x_keys, same_ed_key}, signature::{compact_author_key, sign_packet}}, packets::{extract_and_verify, DeliveryTokenData, DeliveryTokenRegisterData, MessageData, Packet, PacketGenerationError, PacketHeader, PacketReadingError, SealedMessageData}};
+
+/// Generate a new delivery token.
+///
+/// returns !
+///
+/// (token: String, token_hash: String)
+///
+/// The token is given to friends, only its hash is registered on the relay.
+pub fn generate_delivery_token() -> (String, String) {
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    let token = URL_SAFE.encode(token);
+
+    let hash = delivery_token_hash(&token);
+    (token, hash)
+}
+
+pub fn delivery_token_hash(token: &str) -> String {
+    URL_SAFE.encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Relay side check of the token presented by a sealed message against the hash registered by its
+/// recipient
+pub fn verify_delivery_token(packet: &Packet, registered_hash: &str) -> bool {
+    let Packet::SealedMessage(data) = packet else {
+        return false;
+    };
+    delivery_token_hash(&data.delivery_token).as_bytes().ct_eq(registered_hash.as_bytes()).into()
+}
+
+/// Replace our delivery token: returns the registration packet for the relay followed by the
+/// packets giving the new token to every friend. Sealed messages using the old token will be
+/// refused once the relay processed the registration. Our key is compact for the relay and for the
+/// friends supporting it.
+pub fn rotate_delivery_token(config: &mut Config) -> Result<Vec<Packet>, PacketGenerationError> {
+    let public_ed = fs::read_to_string(&config.me.public_ed_path).map_err(|e| PacketGenerationError::ed_key("me.public_ed_path").with_source(e))?;
+    let private_ed = fs::read_to_string(&config.me.private_ed_path).map_err(|e| PacketGenerationError::signing_key("me.private_ed_path").with_source(e))?;
+    let (token, token_hash) = generate_delivery_token();
+
+    let mut register = Packet::DeliveryTokenRegister(DeliveryTokenRegisterData {
+        headers: PacketHeader {
+            action: String::from("delivery_token_register"),
+            author_key: ed_public_pem_to_raw(&public_ed).map_err(|e| PacketGenerationError::ed_key("me.public_ed_path").with_source(e))?,
+            signature: String::default(),
+            ..Default::default()
+        },
+        token_hash,
+    });
+    sign_packet(&mut register, &private_ed)?;
+
+    let mut packets = vec![register];
+    for friend in config.friends.values() {
+        let mut headers = PacketHeader {
+            action: String::from("delivery_token"),
+            author_key: public_ed.clone(),
+            signature: String::default(),
+            ..Default::default()
+        };
+        compact_author_key(&mut headers, &friend.capabilities);
+        let mut packet = Packet::DeliveryToken(DeliveryTokenData {
+            headers,
+            recipient: friend.public_ed.clone(),
+            token: encrypt_payload(&token, &friend.shared_key)?,
+        });
+        sign_packet(&mut packet, &private_ed)?;
+        packets.push(packet);
+    }
+
+    config.me.delivery_token = token;
+    Ok(packets)
+}
+
+/// Store the delivery token received from a friend. The packet signature must have been verified.
+pub fn store_delivery_token(config: &mut Config, packet: &Packet) -> Result<(), PacketReadingError> {
+    let Packet::DeliveryToken(data) = packet else {
+        return Err(PacketReadingError::unexpected(packet));
+    };
+    let friend = config.friends.values_mut()
+        .find(|friend| same_ed_key(&friend.public_ed, &data.headers.author_key))
+        .ok_or_else(|| PacketReadingError::key("headers.author_key").with_action(&data.headers.action))?;
+
+    friend.delivery_token = decrypt_payload(&data.token, &friend.shared_key)?;
+    Ok(())
+}
+
+/// Encrypt a signed message with an ephemeral x25519 key and the recipient published key, the
+/// result can only be opened by the recipient and tells nothing about the author
+pub fn seal_payload(message: &MessageData, recipient_published: &str) -> Result<String, PacketGenerationError> {
+    let (ephemeral_private, ephemeral_public) = generate_x_keys();
+    let shared_key = generate_shared_key(&ephemeral_private, recipient_published).map_err(|e| PacketGenerationError::shared_key("recipient_published").with_source(e))?;
+    let sealed = encrypt_payload(&serde_json::to_string(message)?, &shared_key)?;
+
+    Ok(format!("{ephemeral_public}.{sealed}"))
+}
+
+/// Open a payload produced by [`seal_payload`] with one of our private published keys (the current
+/// one first, then the ones still in their grace period, see
+/// [`crate::encryption::rotation::private_published_keys`]) and verify the message it contains,
+/// refusing messages written by one of `revoked_keys`.
+pub fn unseal_payload(sealed: &str, private_published_keys: &[String], revoked_keys: &[String]) -> Result<MessageData, PacketReadingError> {
+    let (ephemeral_public, sealed) = sealed.split_once('.').ok_or_else(|| PacketReadingError::data("sealed"))?;
+
+    let message = private_published_keys.iter()
+        .filter_map(|private| generate_shared_key(private, ephemeral_public).ok())
+        .find_map(|shared_key| decrypt_payload(sealed, &shared_key).ok())
+        .ok_or_else(|| PacketReadingError::key("sealed"))?;
+
+    match extract_and_verify(&message, revoked_keys)? {
+        Packet::Message(message) => Ok(message),
+        packet => Err(PacketReadingError::unexpected(&packet)),
+    }
+}
+
+/// Seal a signed message for its recipient, the relay only learns the recipient
+pub fn seal_message(message: &MessageData, recipient_published: &str, delivery_token: &str) -> Result<Packet, PacketGenerationError> {
+    Ok(Packet::SealedMessage(SealedMessageData {
+        headers: PacketHeader {
+            action: String::from("sealed_message"),
+            author_key: String::default(),
+            signature: String::default(),
+            ..Default::default()
+        },
+        recipient: message.recipient.clone(),
+        delivery_token: delivery_token.to_string(),
+        sealed: seal_payload(message, recipient_published)?,
+    }))
+}
+
+/// Open a sealed message, see [`unseal_payload`]. The sealed message must be addressed to the same
+/// recipient as the packet carrying it.
+pub fn unseal_message(packet: &Packet, private_published_keys: &[String], revoked_keys: &[String]) -> Result<Packet, PacketReadingError> {
+    let Packet::SealedMessage(data) = packet else {
+        return Err(PacketReadingError::unexpected(packet));
+    };
+
+    let message = unseal_payload(&data.sealed, private_published_keys, revoked_keys)?;
+    if message.recipient != data.recipient {
+        return Err(PacketReadingError::data("recipient").with_action(&data.headers.action));
+    }
+    Ok(Packet::Message(message))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{encryption::{keys::{generate_ed_keys, generate_x_keys, same_ed_key}, signature::sign_packet}, packets::{MessageData, Packet, PacketHeader, PacketReadingError}};
+
+    use super::{generate_delivery_token, seal_message, unseal_message, verify_delivery_token};
+
+    #[test]
+    fn test_sealed_message() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let (recipient_private, recipient_public) = generate_x_keys();
+        let (token, token_hash) = generate_delivery_token();
+
+        let mut packet = Packet::Message(MessageData {
+            headers: PacketHeader { action: String::from("message"), author_key: public_ed.clone(), signature: String::default(), ..Default::default() },
+            recipient: String::from("recipient"),
+            content: String::from("hello"),
+            ..Default::default()
+        });
+        sign_packet(&mut packet, &private_ed).expect("Unable to sign message");
+        let Packet::Message(message) = packet else { unreachable!() };
+
+        let sealed = seal_message(&message, &recipient_public, &token).expect("Unable to seal message");
+        let Packet::SealedMessage(data) = &sealed else { unreachable!() };
+        assert!(data.headers.author_key.is_empty());
+        assert!(!data.sealed.contains("hello"));
+        assert!(verify_delivery_token(&sealed, &token_hash));
+        assert!(!verify_delivery_token(&sealed, &generate_delivery_token().1));
+
+        let revoked = [public_ed.clone()];
+        assert!(matches!(unseal_message(&sealed, std::slice::from_ref(&recipient_private), &revoked), Err(PacketReadingError::Revoked { .. })));
+
+        let Ok(Packet::Message(opened)) = unseal_message(&sealed, &[recipient_private], &[]) else {
+            panic!("Unable to unseal message");
+        };
+        assert!(same_ed_key(&opened.headers.author_key, &public_ed));
+        assert_eq!(opened.content, "hello");
+    }
+}
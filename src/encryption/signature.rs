@@ -1,8 +1,8 @@
 use std::{env, str::FromStr};
 
-use ed25519_dalek::{Signature as EdSignature, Signer, SigningKey, VerifyingKey, pkcs8::{DecodePrivateKey, DecodePublicKey}};
+use ed25519_dalek::{Signature as EdSignature, Signer, SigningKey, pkcs8::DecodePrivateKey};
 
-use crate::packets::{Packet, PacketGenerationError, PacketReadingError};
+use crate::{config::Config, devices::devices_signature_payload, encryption::{keys::{decode_ed_public, encode_ed_public}, revocation::is_revoked}, packets::{protocol::COMPACT_KEYS, Packet, PacketGenerationError, PacketHeader, PacketReadingError}};
 pub trait Signature {
     fn get_signature_payload(&self) -> String;
     fn get_author_key(&self) -> &str;
@@ -12,68 +12,154 @@ pub trait Signature {
 
 impl Signature for Packet {
     fn get_signature_payload(&self) -> String {
-        match self {
+        let payload = match self {
             Packet::Login(request_data) => {
-                format!("{}{}", request_data.headers.action, request_data.headers.author_key)
+                format!("{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.capabilities.join(","))
             }
             Packet::Message(request_data) => {
-                format!("{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.sent_at, request_data.content)
+                // deniable messages are authenticated by their mac, the signature only covers the headers
+                let content = if request_data.mac.is_empty() { request_data.content.as_str() } else { "" };
+                format!("{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.sent_at, content, request_data.recipient_device)
             }
             Packet::FriendRequest(request_data) => {
-                format!("{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient)
+                format!("{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.author_x, request_data.capabilities.join(","), request_data.kem_ciphertext)
             }
             Packet::RetrievePublished(request_data) => {
-                format!("{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.key)
+                format!("{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.key, devices_signature_payload(&request_data.devices), request_data.kem_key)
             }
             Packet::Register(request_data) => {
-                format!("{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.author_published)
+                format!("{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.author_published, request_data.author_kem_published)
             }
             Packet::Announcement(request_data) => {
                 format!("{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.message)
             }
-            Packet::Error(request_data) => {
+            Packet::Error(request_data) if request_data.headers.version == 0 => {
                 format!("{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.message)
             }
+            // the code never contains ':', which separates it from the message
+            Packet::Error(request_data) => {
+                format!("{}{}{}:{}", request_data.headers.action, request_data.headers.author_key, request_data.code, request_data.message)
+            }
+            Packet::PublishedRotation(request_data) => {
+                format!("{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.old_published, request_data.new_published, request_data.grace_until)
+            }
+            Packet::Revoke(request_data) => {
+                format!("{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.certificate.get_signature_payload(), request_data.certificate.signature)
+            }
+            Packet::DeviceList(request_data) => {
+                format!("{}{}{}", request_data.headers.action, request_data.headers.author_key, devices_signature_payload(&request_data.devices))
+            }
+            Packet::DeviceLinkRequest(request_data) => {
+                format!("{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.device_id, request_data.device_published)
+            }
+            Packet::DeviceLinkResponse(request_data) => {
+                format!("{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.accepted, devices_signature_payload(std::slice::from_ref(&request_data.certificate)))
+            }
+            Packet::GroupCreate(request_data) => {
+                format!("{}{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.group_id, request_data.name, keys_payload(&request_data.members), keys_payload(&request_data.admins))
+            }
+            Packet::GroupInvite(request_data) => {
+                format!("{}{}{}{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.group_id, request_data.name, request_data.member, keys_payload(&request_data.members), keys_payload(&request_data.admins), request_data.epoch)
+            }
+            Packet::GroupLeave(request_data) => {
+                format!("{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.group_id, request_data.epoch)
+            }
+            Packet::GroupKick(request_data) => {
+                format!("{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.group_id, request_data.member, request_data.epoch)
+            }
+            Packet::SenderKey(request_data) => {
+                format!("{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.group_id, request_data.epoch, request_data.key)
+            }
+            Packet::GroupMessage(request_data) => {
+                format!("{}{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.group_id, request_data.epoch, request_data.sent_at, request_data.content)
+            }
+            Packet::BlobUpload(request_data) | Packet::BlobChunk(request_data) => {
+                format!("{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.blob_id, request_data.offset, request_data.last, request_data.data)
+            }
+            Packet::BlobDownload(request_data) | Packet::BlobDelete(request_data) => {
+                format!("{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.blob_id, request_data.offset)
+            }
+            Packet::RecoveryShare(request_data) => {
+                format!("{}{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.backup_id, request_data.threshold, request_data.share, request_data.backup)
+            }
+            Packet::RecoveryRequest(request_data) => {
+                format!("{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.owner, request_data.backup_id, request_data.recovery_x)
+            }
+            Packet::RecoveryResponse(request_data) => {
+                format!("{}{}{}{}{}{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.owner, request_data.backup_id, request_data.threshold, request_data.holder_x, request_data.share, request_data.backup)
+            }
+            Packet::SealedMessage(request_data) => {
+                format!("{}{}{}{}", request_data.headers.action, request_data.recipient, request_data.delivery_token, request_data.sealed)
+            }
+            Packet::DeliveryTokenRegister(request_data) => {
+                format!("{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.token_hash)
+            }
+            Packet::DeliveryToken(request_data) => {
+                format!("{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.token)
+            }
+            Packet::MailboxDeliver(request_data) => {
+                format!("{}{}{}", request_data.headers.action, request_data.mailbox, request_data.payload)
+            }
+            Packet::MailboxPoll(request_data) => {
+                format!("{}{}", request_data.headers.action, request_data.mailboxes.concat())
+            }
+            Packet::Pseudonym(request_data) => {
+                format!("{}{}{}{}", request_data.headers.action, request_data.headers.author_key, request_data.recipient, request_data.certificate)
+            }
+            Packet::Ack(request_data) => {
+                format!("{}{}", request_data.headers.action, request_data.headers.author_key)
+            }
+            Packet::Nack(request_data) => {
+                format!("{}{}{}:{}", request_data.headers.action, request_data.headers.author_key, request_data.code, request_data.message)
+            }
+        };
+
+        // packets written before the version field keep their original payload, the request id
+        // is covered so that a response can't be replayed for another request. It is prefixed by
+        // its length so that it can't be moved into the payload.
+        let headers = self.headers();
+        match headers.version {
+            0 => payload,
+            version => format!("v{version}:{}:{}{payload}", headers.request_id.len(), headers.request_id),
         }
     }
 
     fn get_author_key(&self) -> &str {
-        match self {
-            Packet::Login(request_data) => &request_data.headers.author_key,
-            Packet::Message(request_data) => &request_data.headers.author_key,
-            Packet::FriendRequest(request_data) => &request_data.headers.author_key,
-            Packet::RetrievePublished(request_data) => &request_data.headers.author_key,
-            Packet::Register(request_data) => &request_data.headers.author_key,
-            Packet::Announcement(request_data) => &request_data.headers.author_key,
-            Packet::Error(request_data) => &request_data.headers.author_key
-        }
+        &self.headers().author_key
     }
 
     fn get_signature(&self) -> &str {
-        match self {
-            Packet::Login(request_data) => &request_data.headers.signature,
-            Packet::Message(request_data) => &request_data.headers.signature,
-            Packet::FriendRequest(request_data) => &request_data.headers.signature,
-            Packet::RetrievePublished(request_data) => &request_data.headers.signature,
-            Packet::Register(request_data) => &request_data.headers.signature,
-            Packet::Announcement(request_data) => &request_data.headers.signature,
-            Packet::Error(request_data) => &request_data.headers.signature
-        }
+        &self.headers().signature
     }
 
     fn update_signature(&mut self, signature: String) {
-        match self {
-            Packet::Login(request_data) => request_data.headers.signature = signature,
-            Packet::Message(request_data) => request_data.headers.signature = signature,
-            Packet::FriendRequest(request_data) => request_data.headers.signature = signature,
-            Packet::RetrievePublished(request_data) => request_data.headers.signature = signature,
-            Packet::Register(request_data) => request_data.headers.signature = signature,
-            Packet::Announcement(request_data) => request_data.headers.signature = signature,
-            Packet::Error(request_data) => request_data.headers.signature = signature
-        }
+        self.headers_mut().signature = signature;
+    }
+}
+
+/// Signature payload of a list of keys: the number of keys, then each key prefixed by its length,
+/// so that keys can't be moved from a list to the next one
+fn keys_payload(keys: &[String]) -> String {
+    let mut payload = format!("{}:", keys.len());
+    for key in keys {
+        payload.push_str(&format!("{}:{key}", key.len()));
+    }
+    payload
+}
+
+/// Write the author key in its compact form (see [`encode_ed_public`]) when `capabilities`,
+/// negotiated with the recipient, contain [`COMPACT_KEYS`]. Peers that don't support it keep
+/// receiving PEM keys. Must be called before signing.
+pub fn compact_author_key(headers: &mut PacketHeader, capabilities: &[String]) {
+    if !capabilities.iter().any(|capability| capability == COMPACT_KEYS) {
+        return;
+    }
+    if let Ok(key) = decode_ed_public(&headers.author_key) {
+        headers.author_key = encode_ed_public(&key);
     }
 }
 
+/// Keys are signed as they are written, see [`compact_author_key`]
 pub fn sign_packet(packet: &mut Packet, private_key: &str) -> Result<(), PacketGenerationError> {
     let env = env::var("ENV").unwrap_or_default();
 
@@ -83,7 +169,8 @@ pub fn sign_packet(packet: &mut Packet, private_key: &str) -> Result<(), PacketG
     }
 
     let payload = packet.get_signature_payload();
-    let key: SigningKey = SigningKey::from_pkcs8_pem(private_key)?;
+    let key: SigningKey = SigningKey::from_pkcs8_pem(private_key)
+        .map_err(|e| PacketGenerationError::signing_key("private_key").with_source(e).with_action(&packet.headers().action))?;
 
     let signature = key.sign(payload.as_bytes());
     packet.update_signature(signature.to_string());
@@ -101,16 +188,41 @@ pub fn sign_packet(packet: &mut Packet, private_key: &str) -> Result<(), PacketG
 /// Signed payload is author_key + recipent_key + content + sent_at
 /// ## Friend Request
 /// ## Retrieve Published
-pub fn verify_packet_signature(packet: &Packet) -> Result<(), PacketReadingError> {
+///
+/// Packets whose author is among `revoked_keys` (the revoked keys of a configuration) are refused,
+/// see [`crate::encryption::revocation`]
+pub fn verify_packet_signature(packet: &Packet, revoked_keys: &[String]) -> Result<(), PacketReadingError> {
     let environment = env::var("ENV").unwrap_or_default();
 
-    // disable verify_packet_signature in dev env
+    // disable verify_packet_signature in dev env, revocations are still checked
     if environment == "DEV" {
+        return check_not_revoked(packet, revoked_keys)
+    }
+
+    // sealed messages and mailboxes have no outer author, the relay checks their delivery token
+    // or mailbox and the recipient verifies the sealed message once opened
+    if let Packet::SealedMessage(_) | Packet::MailboxDeliver(_) | Packet::MailboxPoll(_) = packet {
         return Ok(())
     }
 
-    let key = VerifyingKey::from_public_key_pem(packet.get_author_key())?;
-    let signature = EdSignature::from_str(packet.get_signature())?;
-    key.verify_strict(packet.get_signature_payload().as_bytes(), &signature)?;
+    let action = &packet.headers().action;
+    let key = decode_ed_public(packet.get_author_key()).map_err(|e| e.with_field("headers.author_key").with_action(action))?;
+    let signature = EdSignature::from_str(packet.get_signature()).map_err(|e| PacketReadingError::from(e).with_action(action))?;
+    key.verify_strict(packet.get_signature_payload().as_bytes(), &signature)
+        .map_err(|e| PacketReadingError::from(e).with_action(action))?;
+
+    check_not_revoked(packet, revoked_keys)
+}
+
+fn check_not_revoked(packet: &Packet, revoked_keys: &[String]) -> Result<(), PacketReadingError> {
+    if let Ok(key) = decode_ed_public(packet.get_author_key())
+        && is_revoked(revoked_keys, &key) {
+        return Err(PacketReadingError::Revoked { action: packet.headers().action.clone(), field: String::from("headers.author_key") });
+    }
     Ok(())
 }
+
+/// Same as [`verify_packet_signature`] with the revoked keys of `config`
+pub fn verify_packet(config: &Config, packet: &Packet) -> Result<(), PacketReadingError> {
+    verify_packet_signature(packet, &config.revoked_keys)
+}
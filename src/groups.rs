@@ -0,0 +1,607 @@
+use std::{collections::HashMap, fmt::Display, fs};
+
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use rand_core::{OsRng, RngCore};
+use uuid::Uuid;
+
+use crate::{config::{Config, Friend, Group, SenderKey}, devices::is_friend_key, encryption::{decrypt_padded_payload, decrypt_payload, encrypt_padded_payload, encrypt_payload, keys::{ed_key_id, same_ed_key}, signature::{compact_author_key, sign_packet, verify_packet}}, packets::{GroupCreateData, GroupInviteData, GroupKickData, GroupLeaveData, GroupMessageData, Packet, PacketGenerationError, PacketHeader, SenderKeyData}};
+
+#[derive(Debug)]
+pub enum GroupError {
+    Io(std::io::Error),
+    Generation(PacketGenerationError),
+    WrongPacket,
+    UnknownGroup,
+    /// Creation or invitation for a group we are already part of
+    GroupExists,
+    UnknownMember,
+    NotAdmin,
+    NotMember,
+    /// Creation or invitation that doesn't list us as a member
+    NotInvited,
+    /// Membership change or sender key of an epoch older than the current one
+    OutdatedEpoch,
+    /// Membership change of a group whose epoch can't be increased anymore
+    LastEpoch,
+    InvalidSignature,
+    MissingSenderKey,
+    Decryption,
+}
+
+impl Display for GroupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroupError::Io(e) => {
+                write!(f, "IO error: {}", e)
+            }
+            GroupError::Generation(e) => {
+                write!(f, "Unable to generate group packet: {}", e)
+            }
+            GroupError::WrongPacket => {
+                write!(f, "Packet is not a group packet")
+            }
+            GroupError::UnknownGroup => {
+                write!(f, "This group does not exist")
+            }
+            GroupError::GroupExists => {
+                write!(f, "This group already exists")
+            }
+            GroupError::UnknownMember => {
+                write!(f, "This user is not a friend, unable to share keys with them")
+            }
+            GroupError::NotAdmin => {
+                write!(f, "Only admins of the group can do this")
+            }
+            GroupError::NotMember => {
+                write!(f, "Author is not a member of the group")
+            }
+            GroupError::NotInvited => {
+                write!(f, "We are not a member of this group")
+            }
+            GroupError::OutdatedEpoch => {
+                write!(f, "Group packet is older than the current epoch")
+            }
+            GroupError::LastEpoch => {
+                write!(f, "Group has reached its last epoch")
+            }
+            GroupError::InvalidSignature => {
+                write!(f, "Group packet has an invalid signature")
+            }
+            GroupError::MissingSenderKey => {
+                write!(f, "No sender key received from this member for the current epoch")
+            }
+            GroupError::Decryption => {
+                write!(f, "Unable to decrypt group message")
+            }
+        }
+    }
+}
+impl std::error::Error for GroupError {}
+
+/// Result of a group packet handled with [`handle_group_packet`]
+pub enum GroupEvent {
+    /// Membership changed (creation, invitation, leave or kick), our sender key has been rotated and
+    /// the packets distributing it must be sent
+    MembershipChanged { group_id: String, distributions: Vec<Packet> },
+    /// We are not part of the group anymore, it has been removed from the configuration
+    Removed { group_id: String },
+    SenderKeyReceived { group_id: String, author: String },
+    Message { group_id: String, author: String, sent_at: String, content: String },
+}
+
+struct Identity {
+    public_ed: String,
+    private_ed: String,
+}
+
+fn identity(config: &Config) -> Result<Identity, GroupError> {
+    Ok(Identity {
+        public_ed: fs::read_to_string(&config.me.public_ed_path)?,
+        private_ed: fs::read_to_string(&config.me.private_ed_path)?,
+    })
+}
+
+fn contains(keys: &[String], key: &str) -> bool {
+    keys.iter().any(|k| same_ed_key(k, key))
+}
+
+fn generate_sender_key() -> String {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    URL_SAFE.encode(key)
+}
+
+/// Headers of a packet to `recipient`, our key being compact if they support it
+fn headers(action: &str, identity: &Identity, friends: &HashMap<String, Friend>, recipient: &str) -> PacketHeader {
+    let mut headers = PacketHeader {
+        action: action.to_string(),
+        author_key: identity.public_ed.clone(),
+        signature: String::default(),
+        ..Default::default()
+    };
+    if let Some(friend) = friends.values().find(|friend| same_ed_key(&friend.public_ed, recipient)) {
+        compact_author_key(&mut headers, &friend.capabilities);
+    }
+    headers
+}
+
+fn signed(mut packet: Packet, identity: &Identity) -> Result<Packet, GroupError> {
+    sign_packet(&mut packet, &identity.private_ed)?;
+    Ok(packet)
+}
+
+/// Epoch following the current one of `group`, used by every membership change
+fn next_epoch(group: &Group) -> Result<u64, GroupError> {
+    group.epoch.checked_add(1).ok_or(GroupError::LastEpoch)
+}
+
+/// Start a new epoch with a fresh sender key and build its distribution to every member we share a
+/// key with. Members that are not friends can't receive it and are skipped.
+fn rotate_sender_key(config: &mut Config, group_id: &str, epoch: u64, identity: &Identity) -> Result<Vec<Packet>, GroupError> {
+    let group = config.groups.get_mut(group_id).ok_or(GroupError::UnknownGroup)?;
+    group.epoch = epoch;
+    group.sender_key = generate_sender_key();
+    group.member_sender_keys.retain(|_, key| key.epoch >= epoch);
+
+    let group = &config.groups[group_id];
+    let mut packets = Vec::with_capacity(group.members.len());
+    for member in group.members.iter().filter(|member| !same_ed_key(member, &identity.public_ed)) {
+        let Some(friend) = config.friends.values().find(|friend| same_ed_key(&friend.public_ed, member)) else {
+            continue;
+        };
+
+        packets.push(signed(Packet::SenderKey(SenderKeyData {
+            headers: headers("sender_key", identity, &config.friends, member),
+            recipient: member.clone(),
+            group_id: group_id.to_string(),
+            epoch,
+            key: encrypt_payload(&group.sender_key, &friend.shared_key)?,
+        }), identity)?);
+    }
+    Ok(packets)
+}
+
+/// Create a group with the given friends (public ed25519 keys), we are its only admin.
+///
+/// Returns the creation packets followed by our sender key distribution.
+pub fn create_group(config: &mut Config, name: &str, members: &[String]) -> Result<(String, Vec<Packet>), GroupError> {
+    let identity = identity(config)?;
+    if !members.iter().all(|member| config.friends.values().any(|friend| same_ed_key(&friend.public_ed, member))) {
+        return Err(GroupError::UnknownMember);
+    }
+
+    let group_id = Uuid::new_v4().to_string();
+    let mut all_members = vec![identity.public_ed.clone()];
+    all_members.extend(members.iter().cloned());
+    let admins = vec![identity.public_ed.clone()];
+
+    let mut packets = Vec::with_capacity(members.len() * 2);
+    for member in members {
+        packets.push(signed(Packet::GroupCreate(GroupCreateData {
+            headers: headers("group_create", &identity, &config.friends, member),
+            recipient: member.clone(),
+            group_id: group_id.clone(),
+            name: name.to_string(),
+            members: all_members.clone(),
+            admins: admins.clone(),
+        }), &identity)?);
+    }
+
+    config.groups.insert(group_id.clone(), Group {
+        id: group_id.clone(),
+        name: name.to_string(),
+        members: all_members,
+        admins,
+        ..Default::default()
+    });
+    packets.extend(rotate_sender_key(config, &group_id, 0, &identity)?);
+
+    Ok((group_id, packets))
+}
+
+/// Invite a friend in a group, only admins can invite.
+///
+/// Returns the invitation sent to every member and the distribution of our new sender key.
+pub fn invite_member(config: &mut Config, group_id: &str, member: &str) -> Result<Vec<Packet>, GroupError> {
+    let identity = identity(config)?;
+    let group = config.groups.get_mut(group_id).ok_or(GroupError::UnknownGroup)?;
+    if !contains(&group.admins, &identity.public_ed) {
+        return Err(GroupError::NotAdmin);
+    }
+    if !config.friends.values().any(|friend| same_ed_key(&friend.public_ed, member)) {
+        return Err(GroupError::UnknownMember);
+    }
+
+    let epoch = next_epoch(group)?;
+    if !contains(&group.members, member) {
+        group.members.push(member.to_string());
+    }
+
+    let mut packets = Vec::with_capacity(group.members.len() * 2);
+    for recipient in group.members.iter().filter(|recipient| !same_ed_key(recipient, &identity.public_ed)) {
+        packets.push(signed(Packet::GroupInvite(GroupInviteData {
+            headers: headers("group_invite", &identity, &config.friends, recipient),
+            recipient: recipient.clone(),
+            group_id: group_id.to_string(),
+            name: group.name.clone(),
+            member: member.to_string(),
+            members: group.members.clone(),
+            admins: group.admins.clone(),
+            epoch,
+        }), &identity)?);
+    }
+    packets.extend(rotate_sender_key(config, group_id, epoch, &identity)?);
+
+    Ok(packets)
+}
+
+/// Remove a member from a group, only admins can kick. The kicked member is notified too.
+pub fn kick_member(config: &mut Config, group_id: &str, member: &str) -> Result<Vec<Packet>, GroupError> {
+    let identity = identity(config)?;
+    let group = config.groups.get_mut(group_id).ok_or(GroupError::UnknownGroup)?;
+    if !contains(&group.admins, &identity.public_ed) {
+        return Err(GroupError::NotAdmin);
+    }
+    if !contains(&group.members, member) {
+        return Err(GroupError::NotMember);
+    }
+
+    let epoch = next_epoch(group)?;
+    let mut packets = Vec::with_capacity(group.members.len() * 2);
+    for recipient in group.members.iter().filter(|recipient| !same_ed_key(recipient, &identity.public_ed)) {
+        packets.push(signed(Packet::GroupKick(GroupKickData {
+            headers: headers("group_kick", &identity, &config.friends, recipient),
+            recipient: recipient.clone(),
+            group_id: group_id.to_string(),
+            member: member.to_string(),
+            epoch,
+        }), &identity)?);
+    }
+
+    group.members.retain(|m| !same_ed_key(m, member));
+    group.admins.retain(|m| !same_ed_key(m, member));
+    group.member_sender_keys.retain(|m, _| !same_ed_key(m, member));
+    packets.extend(rotate_sender_key(config, group_id, epoch, &identity)?);
+
+    Ok(packets)
+}
+
+/// Leave a group and remove it from the configuration
+pub fn leave_group(config: &mut Config, group_id: &str) -> Result<Vec<Packet>, GroupError> {
+    let identity = identity(config)?;
+    let group = config.groups.remove(group_id).ok_or(GroupError::UnknownGroup)?;
+    let epoch = next_epoch(&group)?;
+
+    group.members.iter()
+        .filter(|recipient| !same_ed_key(recipient, &identity.public_ed))
+        .map(|recipient| signed(Packet::GroupLeave(GroupLeaveData {
+            headers: headers("group_leave", &identity, &config.friends, recipient),
+            recipient: recipient.clone(),
+            group_id: group_id.to_string(),
+            epoch,
+        }), &identity))
+        .collect()
+}
+
+/// Encrypt a message with our sender key, one copy is generated for each member
+pub fn send_group_message(config: &Config, group_id: &str, content: &str, sent_at: &str) -> Result<Vec<Packet>, GroupError> {
+    let identity = identity(config)?;
+    let group = config.groups.get(group_id).ok_or(GroupError::UnknownGroup)?;
+    let content = encrypt_padded_payload(content, &group.sender_key, group.padding)?;
+
+    group.members.iter()
+        .filter(|recipient| !same_ed_key(recipient, &identity.public_ed))
+        .map(|recipient| signed(Packet::GroupMessage(GroupMessageData {
+            headers: headers("group_message", &identity, &config.friends, recipient),
+            recipient: recipient.clone(),
+            group_id: group_id.to_string(),
+            epoch: group.epoch,
+            sent_at: sent_at.to_string(),
+            content: content.clone(),
+        }), &identity))
+        .collect()
+}
+
+/// Creations and invitations adding us to a group are only accepted from friends, for a group we
+/// don't know yet and listing us as a member
+fn check_joined_group(config: &Config, identity: &Identity, author: &str, group_id: &str, members: &[String], admins: &[String]) -> Result<(), GroupError> {
+    if config.groups.contains_key(group_id) {
+        return Err(GroupError::GroupExists);
+    }
+    if !config.friends.values().any(|friend| same_ed_key(&friend.public_ed, author)) {
+        return Err(GroupError::UnknownMember);
+    }
+    if !contains(admins, author) {
+        return Err(GroupError::NotAdmin);
+    }
+    if !contains(members, &identity.public_ed) {
+        return Err(GroupError::NotInvited);
+    }
+    Ok(())
+}
+
+/// Identity key of the author of a group packet: the identity of the friend owning the device that
+/// wrote it, or the author key itself. Members and admins are listed by identity key.
+fn author_identity(config: &Config, author: &str) -> String {
+    config.friends.values()
+        .find(|friend| is_friend_key(friend, author, &config.revoked_keys))
+        .map(|friend| friend.public_ed.clone())
+        .unwrap_or_else(|| author.to_string())
+}
+
+/// Verify and apply a group packet received from another member.
+/// The caller is responsible for writing the configuration and sending the returned packets.
+pub fn handle_group_packet(config: &mut Config, packet: &Packet) -> Result<GroupEvent, GroupError> {
+    verify_packet(config, packet).map_err(|_| GroupError::InvalidSignature)?;
+    let identity = identity(config)?;
+    let author = author_identity(config, &packet.headers().author_key);
+
+    match packet {
+        Packet::GroupCreate(data) => {
+            check_joined_group(config, &identity, &author, &data.group_id, &data.members, &data.admins)?;
+            config.groups.insert(data.group_id.clone(), Group {
+                id: data.group_id.clone(),
+                name: data.name.clone(),
+                members: data.members.clone(),
+                admins: data.admins.clone(),
+                ..Default::default()
+            });
+            let distributions = rotate_sender_key(config, &data.group_id, 0, &identity)?;
+            Ok(GroupEvent::MembershipChanged { group_id: data.group_id.clone(), distributions })
+        }
+        Packet::GroupInvite(data) => {
+            if same_ed_key(&data.member, &identity.public_ed) {
+                // we are the invited member, the invitation must come from one of the admins it lists
+                check_joined_group(config, &identity, &author, &data.group_id, &data.members, &data.admins)?;
+                config.groups.insert(data.group_id.clone(), Group {
+                    id: data.group_id.clone(),
+                    name: data.name.clone(),
+                    members: data.members.clone(),
+                    admins: data.admins.clone(),
+                    ..Default::default()
+                });
+            } else {
+                let group = config.groups.get_mut(&data.group_id).ok_or(GroupError::UnknownGroup)?;
+                if !contains(&group.admins, &author) {
+                    return Err(GroupError::NotAdmin);
+                }
+                if data.epoch <= group.epoch {
+                    return Err(GroupError::OutdatedEpoch);
+                }
+                if !contains(&group.members, &data.member) {
+                    group.members.push(data.member.clone());
+                }
+            }
+            let distributions = rotate_sender_key(config, &data.group_id, data.epoch, &identity)?;
+            Ok(GroupEvent::MembershipChanged { group_id: data.group_id.clone(), distributions })
+        }
+        Packet::GroupKick(data) => {
+            let group = config.groups.get_mut(&data.group_id).ok_or(GroupError::UnknownGroup)?;
+            if !contains(&group.admins, &author) {
+                return Err(GroupError::NotAdmin);
+            }
+            if data.epoch <= group.epoch {
+                return Err(GroupError::OutdatedEpoch);
+            }
+            if same_ed_key(&data.member, &identity.public_ed) {
+                config.groups.remove(&data.group_id);
+                return Ok(GroupEvent::Removed { group_id: data.group_id.clone() });
+            }
+
+            group.members.retain(|m| !same_ed_key(m, &data.member));
+            group.admins.retain(|m| !same_ed_key(m, &data.member));
+            group.member_sender_keys.retain(|m, _| !same_ed_key(m, &data.member));
+            let distributions = rotate_sender_key(config, &data.group_id, data.epoch, &identity)?;
+            Ok(GroupEvent::MembershipChanged { group_id: data.group_id.clone(), distributions })
+        }
+        Packet::GroupLeave(data) => {
+            let group = config.groups.get_mut(&data.group_id).ok_or(GroupError::UnknownGroup)?;
+            if !contains(&group.members, &author) {
+                return Err(GroupError::NotMember);
+            }
+
+            group.members.retain(|m| !same_ed_key(m, &author));
+            group.admins.retain(|m| !same_ed_key(m, &author));
+            group.member_sender_keys.retain(|m, _| !same_ed_key(m, &author));
+            // the epoch announced by the leaver is ignored, it could lock the group by picking the last one
+            let epoch = next_epoch(group)?;
+            let distributions = rotate_sender_key(config, &data.group_id, epoch, &identity)?;
+            Ok(GroupEvent::MembershipChanged { group_id: data.group_id.clone(), distributions })
+        }
+        Packet::SenderKey(data) => {
+            let friend = config.friends.values().find(|friend| same_ed_key(&friend.public_ed, &author)).ok_or(GroupError::UnknownMember)?;
+            let key = decrypt_payload(&data.key, &friend.shared_key).map_err(|_| GroupError::Decryption)?;
+
+            let group = config.groups.get_mut(&data.group_id).ok_or(GroupError::UnknownGroup)?;
+            if !contains(&group.members, &author) {
+                return Err(GroupError::NotMember);
+            }
+            if data.epoch < group.epoch {
+                return Err(GroupError::OutdatedEpoch);
+            }
+            group.member_sender_keys.insert(ed_key_id(&author), SenderKey { epoch: data.epoch, key });
+            Ok(GroupEvent::SenderKeyReceived { group_id: data.group_id.clone(), author: author.clone() })
+        }
+        Packet::GroupMessage(data) => {
+            let group = config.groups.get(&data.group_id).ok_or(GroupError::UnknownGroup)?;
+            if !contains(&group.members, &author) {
+                return Err(GroupError::NotMember);
+            }
+            let sender_key = group.member_sender_keys.get(&ed_key_id(&author))
+                .filter(|key| key.epoch == data.epoch)
+                .ok_or(GroupError::MissingSenderKey)?;
+            let capabilities = config.friends.values().find(|friend| same_ed_key(&friend.public_ed, &author)).map(|friend| friend.capabilities.as_slice()).unwrap_or_default();
+            let content = decrypt_padded_payload(&data.content, &sender_key.key, capabilities).map_err(|_| GroupError::Decryption)?;
+
+            Ok(GroupEvent::Message {
+                group_id: data.group_id.clone(),
+                author: author.clone(),
+                sent_at: data.sent_at.clone(),
+                content,
+            })
+        }
+        _ => Err(GroupError::WrongPacket),
+    }
+}
+
+impl From<std::io::Error> for GroupError {
+    fn from(err: std::io::Error) -> Self {
+        GroupError::Io(err)
+    }
+}
+
+impl From<PacketGenerationError> for GroupError {
+    fn from(err: PacketGenerationError) -> Self {
+        GroupError::Generation(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{config::Config, encryption::{keys::ed_key_id, signature::{sign_packet, Signature}}, packets::{GroupCreateData, GroupInviteData, GroupKickData, GroupLeaveData, Packet, PacketHeader}, test::TestUser};
+
+    use super::{create_group, handle_group_packet, leave_group, send_group_message, GroupError, GroupEvent};
+
+    fn deliver(config: &mut Config, packets: Vec<Packet>, recipient: &str) -> Vec<Packet> {
+        let mut answers = Vec::new();
+        for packet in packets {
+            let for_recipient = match &packet {
+                Packet::GroupCreate(data) => data.recipient == recipient,
+                Packet::SenderKey(data) => data.recipient == recipient,
+                Packet::GroupMessage(data) => data.recipient == recipient,
+                _ => false,
+            };
+            if !for_recipient {
+                continue;
+            }
+            if let Ok(GroupEvent::MembershipChanged { distributions, .. }) = handle_group_packet(config, &packet) {
+                answers.extend(distributions);
+            }
+        }
+        answers
+    }
+
+    fn signed(mut packet: Packet, config: &Config) -> Packet {
+        let private_ed = fs::read_to_string(&config.me.private_ed_path).expect("Unable to read key");
+        sign_packet(&mut packet, &private_ed).expect("Unable to sign packet");
+        packet
+    }
+
+    fn headers(action: &str, author: &str) -> PacketHeader {
+        PacketHeader { action: action.to_string(), author_key: author.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn test_group_message() {
+        let mut alice = TestUser::new("alice");
+        let mut bob = TestUser::new("bob");
+        alice.befriend(&mut bob);
+
+        let (group_id, packets) = create_group(&mut alice.config, "friends", std::slice::from_ref(&bob.public_ed)).expect("Unable to create group");
+        // bob supports compact keys
+        assert!(packets.iter().all(|packet| packet.headers().author_key == ed_key_id(&alice.public_ed)));
+        let bob_distribution = deliver(&mut bob.config, packets, &bob.public_ed);
+        deliver(&mut alice.config, bob_distribution, &alice.public_ed);
+
+        let messages = send_group_message(&alice.config, &group_id, "hello group", "2025-01-01T00:00:00Z").expect("Unable to send message");
+        let Ok(GroupEvent::Message { content, .. }) = handle_group_packet(&mut bob.config, &messages[0]) else {
+            panic!("Unable to read group message");
+        };
+        assert_eq!(content, "hello group");
+        assert_eq!(alice.config.groups[&group_id].member_sender_keys.len(), 1);
+    }
+
+    #[test]
+    fn test_group_takeover_refused() {
+        let mut alice = TestUser::new("alice");
+        let mut bob = TestUser::new("bob");
+        let mut carol = TestUser::new("carol");
+        alice.befriend(&mut bob);
+        alice.befriend(&mut carol);
+        bob.befriend(&mut carol);
+
+        let members = vec![alice.public_ed.clone(), bob.public_ed.clone(), carol.public_ed.clone()];
+        let (group_id, packets) = create_group(&mut alice.config, "friends", &members[1..]).expect("Unable to create group");
+        deliver(&mut bob.config, packets, &bob.public_ed);
+        assert_eq!(bob.config.groups[&group_id].admins, vec![alice.public_ed.clone()]);
+
+        // carol is a member but not an admin, she can't recreate the group or invite bob again to take it over
+        let create = signed(Packet::GroupCreate(GroupCreateData {
+            headers: headers("group_create", &carol.public_ed),
+            recipient: bob.public_ed.clone(),
+            group_id: group_id.clone(),
+            name: String::from("takeover"),
+            members: members.clone(),
+            admins: vec![carol.public_ed.clone()],
+        }), &carol.config);
+        assert!(matches!(handle_group_packet(&mut bob.config, &create), Err(GroupError::GroupExists)));
+
+        let invite = signed(Packet::GroupInvite(GroupInviteData {
+            headers: headers("group_invite", &carol.public_ed),
+            recipient: bob.public_ed.clone(),
+            group_id: group_id.clone(),
+            name: String::from("takeover"),
+            member: bob.public_ed.clone(),
+            members: members.clone(),
+            admins: vec![carol.public_ed.clone()],
+            epoch: 1,
+        }), &carol.config);
+        assert!(matches!(handle_group_packet(&mut bob.config, &invite), Err(GroupError::GroupExists)));
+        assert_eq!(bob.config.groups[&group_id].name, "friends");
+        assert_eq!(bob.config.groups[&group_id].admins, vec![alice.public_ed.clone()]);
+
+        // a replayed kick of the current epoch is refused
+        let kick = signed(Packet::GroupKick(GroupKickData {
+            headers: headers("group_kick", &alice.public_ed),
+            recipient: bob.public_ed.clone(),
+            group_id: group_id.clone(),
+            member: carol.public_ed.clone(),
+            epoch: 0,
+        }), &alice.config);
+        assert!(matches!(handle_group_packet(&mut bob.config, &kick), Err(GroupError::OutdatedEpoch)));
+        assert_eq!(bob.config.groups[&group_id].members.len(), 3);
+    }
+    #[test]
+    fn test_group_lists_and_epochs() {
+        let mut alice = TestUser::new("alice");
+        let mut bob = TestUser::new("bob");
+        let mut carol = TestUser::new("carol");
+        alice.befriend(&mut bob);
+        alice.befriend(&mut carol);
+        bob.befriend(&mut carol);
+
+        let members = [alice.public_ed.clone(), bob.public_ed.clone(), carol.public_ed.clone()];
+        let (group_id, packets) = create_group(&mut alice.config, "friends", &members[1..]).expect("Unable to create group");
+        let created: Vec<Packet> = packets.into_iter().filter(|packet| matches!(packet, Packet::GroupCreate(data) if data.recipient == bob.public_ed)).collect();
+
+        // moving a key from the members to the admins breaks the signature
+        let data = created[0].to_json().expect("Unable to encode packet");
+        let Ok(mut moved) = Packet::from_json(&data) else {
+            panic!("Unable to decode packet");
+        };
+        if let Packet::GroupCreate(data) = &mut moved {
+            data.members.pop();
+            data.admins.insert(0, carol.public_ed.clone());
+        }
+        assert_ne!(moved.get_signature_payload(), created[0].get_signature_payload());
+        assert!(matches!(handle_group_packet(&mut bob.config, &moved), Err(GroupError::InvalidSignature)));
+
+        deliver(&mut bob.config, created, &bob.public_ed);
+        assert_eq!(bob.config.groups[&group_id].epoch, 0);
+
+        // the epoch announced by a leaver is ignored
+        let leave = signed(Packet::GroupLeave(GroupLeaveData {
+            headers: headers("group_leave", &carol.public_ed),
+            recipient: bob.public_ed.clone(),
+            group_id: group_id.clone(),
+            epoch: u64::MAX,
+        }), &carol.config);
+        assert!(handle_group_packet(&mut bob.config, &leave).is_ok());
+        assert_eq!(bob.config.groups[&group_id].epoch, 1);
+
+        bob.config.groups.get_mut(&group_id).expect("Group removed").epoch = u64::MAX;
+        assert!(matches!(leave_group(&mut bob.config, &group_id), Err(GroupError::LastEpoch)));
+    }
+}
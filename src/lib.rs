@@ -1,11 +1,16 @@
-use std::{env, fs};
+use std::{env, fs, time::{SystemTime, UNIX_EPOCH}};
 
-use crate::encryption::keys::{generate_ed_keys, generate_x_keys};
+use crate::encryption::{keys::{generate_ed_keys, generate_x_keys}, revocation::generate_revocation_certificate};
 
 pub mod packets;
 pub mod encryption;
 pub mod config;
 pub mod transactions;
+pub mod devices;
+pub mod groups;
+pub mod attachments;
+pub mod recovery;
+pub mod mailbox;
 
 /// Generate the basics configuration files along with default values
 /// Path of the file is taken from the PLUME_CONFIG environment variable
@@ -28,13 +33,17 @@ pub fn init() {
     // for each needed key, create one
     let (private_ed, public_ed) = generate_ed_keys();
     let (private_published, public_published) = generate_x_keys();
-    fs::write(format!("{config_path}/keys/private_ed.pem"), private_ed).expect("Unable to write pivate ed key to disk");
-    fs::write(format!("{config_path}/keys/public_ed.pem"), public_ed).expect("Unable to write pivate ed key to disk");
+    fs::write(format!("{config_path}/keys/private_ed.pem"), &private_ed).expect("Unable to write pivate ed key to disk");
+    fs::write(format!("{config_path}/keys/public_ed.pem"), &public_ed).expect("Unable to write pivate ed key to disk");
     fs::write(format!("{config_path}/keys/public_published.pem"), public_published).expect("Unable to write pivate ed key to disk");
     fs::write(format!("{config_path}/keys/private_published.pem"), private_published).expect("Unable to write pivate ed key to disk");
 
     println!("Keys saved");
 
+    // the revocation certificate is generated right away, it should be moved somewhere safe by the user
+    let certificate = generate_revocation_certificate(&private_ed, &public_ed, "compromised").expect("Unable to generate revocation certificate");
+    fs::write(format!("{config_path}/keys/revocation.json"), serde_json::to_vec(&certificate).expect("Unable to serialize revocation certificate")).expect("Unable to write revocation certificate to disk");
+
     // generate the configurat_ion
     let json = serde_json::json!({
         "@me": {
@@ -54,15 +63,80 @@ pub fn init() {
     println!("Wrote file");
 }
 
+/// Current unix timestamp in seconds, used for every expiry stored in the configuration
+pub fn current_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
 #[cfg(test)]
-mod test {
-    use std::{env, fs};
+pub(crate) mod test {
+    use std::{env, fs, path::PathBuf, sync::{Mutex, MutexGuard}};
 
     use dotenv::dotenv;
-    use crate::{config::get_config, init};
+    use crate::{config::{get_config, Config, Friend}, encryption::keys::{generate_ed_keys, generate_shared_key, generate_x_keys}, init, packets::protocol::local_capabilities};
+
+    /// Held by the tests using the PLUME_CONFIG folder, which is deleted by [`test_initialisation`]
+    static CONFIG_FOLDER: Mutex<()> = Mutex::new(());
+
+    /// Lock the configuration folder, creating it if needed
+    pub(crate) fn config_folder() -> MutexGuard<'static, ()> {
+        let guard = CONFIG_FOLDER.lock().unwrap_or_else(|e| e.into_inner());
+        dotenv().ok();
+        init();
+        guard
+    }
+
+    /// User whose identity keys are written in a temporary directory, removed when dropped
+    pub(crate) struct TestUser {
+        pub(crate) config: Config,
+        pub(crate) public_ed: String,
+        pub(crate) private_ed: String,
+        dir: PathBuf,
+    }
+
+    impl TestUser {
+        pub(crate) fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("plume_{name}_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).expect("Unable to create key directory");
+            let (private_ed, public_ed) = generate_ed_keys();
+            fs::write(dir.join("private_ed.pem"), &private_ed).expect("Unable to write key");
+            fs::write(dir.join("public_ed.pem"), &public_ed).expect("Unable to write key");
+
+            let mut config = Config::default();
+            config.me.private_ed_path = dir.join("private_ed.pem").to_string_lossy().to_string();
+            config.me.public_ed_path = dir.join("public_ed.pem").to_string_lossy().to_string();
+            TestUser { config, public_ed, private_ed, dir }
+        }
+
+        /// Add both users to each other's friends, with a fresh shared key and the capabilities they
+        /// advertise
+        pub(crate) fn befriend(&mut self, other: &mut TestUser) {
+            let (own_x, own_public_x) = generate_x_keys();
+            let (other_x, other_public_x) = generate_x_keys();
+            self.config.friends.insert(other.public_ed.clone(), Friend {
+                public_ed: other.public_ed.clone(),
+                shared_key: generate_shared_key(&own_x, &other_public_x).expect("Unable to generate shared key"),
+                capabilities: local_capabilities(&other.config.me),
+                ..Default::default()
+            });
+            other.config.friends.insert(self.public_ed.clone(), Friend {
+                public_ed: self.public_ed.clone(),
+                shared_key: generate_shared_key(&other_x, &own_public_x).expect("Unable to generate shared key"),
+                capabilities: local_capabilities(&self.config.me),
+                ..Default::default()
+            });
+        }
+    }
+
+    impl Drop for TestUser {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
 
     #[test]
     fn test_initialisation() {
+        let _guard = CONFIG_FOLDER.lock().unwrap_or_else(|e| e.into_inner());
         dotenv().ok();
         let config_path = env::var("PLUME_CONFIG").expect("Unableto access env var");
         // first, delete config folder if it already exist
@@ -0,0 +1,107 @@
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{config::{Config, Friend}, encryption::{keys::{decode_ed_public, same_ed_key, SharedGenerationError}, sealed::{seal_payload, unseal_payload}}, packets::{MailboxDeliverData, MailboxPollData, MessageData, Packet, PacketGenerationError, PacketHeader, PacketReadingError}};
+
+/// Lifetime of a mailbox address: one day
+pub const MAILBOX_PERIOD: u64 = 60 * 60 * 24;
+
+/// Epoch of the mailbox addresses at the given unix timestamp
+pub fn mailbox_epoch(timestamp: u64) -> u64 {
+    timestamp / MAILBOX_PERIOD
+}
+
+/// Address of the mailbox receiving the messages sent to `recipient_ed` by its friend during
+/// `epoch`. It is derived from their shared key so only the two friends can compute it, and each
+/// direction of the conversation gets its own mailbox.
+pub fn mailbox_id(shared_key: &str, recipient_ed: &str, epoch: u64) -> Result<String, SharedGenerationError> {
+    let shared: [u8; 32] = URL_SAFE.decode(shared_key)?.try_into()?;
+    // identity keys are hashed in their raw form so that their encoding doesn't change the address
+    let recipient = decode_ed_public(recipient_ed).map_err(|_| SharedGenerationError::InvalidKeyError)?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&shared).map_err(|_| SharedGenerationError::InvalidKeyError)?;
+    mac.update(b"plume_mailbox");
+    mac.update(&epoch.to_be_bytes());
+    mac.update(recipient.as_bytes());
+
+    Ok(URL_SAFE.encode(mac.finalize().into_bytes()))
+}
+
+/// Every mailbox we need to poll at `now`: for each friend, the current epoch and the previous one
+/// to get the messages sent right before the rotation or with a clock slightly late.
+pub fn inbound_mailboxes(config: &Config, own_public_ed: &str, now: u64) -> Vec<String> {
+    let epoch = mailbox_epoch(now);
+
+    config.friends.values()
+        .flat_map(|friend| [epoch.saturating_sub(1), epoch].map(|epoch| mailbox_id(&friend.shared_key, own_public_ed, epoch)))
+        .filter_map(Result::ok)
+        .collect::<std::collections::BTreeSet<String>>()
+        .into_iter()
+        .collect()
+}
+
+/// Poll packet for the given mailboxes, it is not signed as it must not reveal who is polling
+pub fn poll_packet(mailboxes: Vec<String>) -> Packet {
+    Packet::MailboxPoll(MailboxPollData {
+        headers: PacketHeader {
+            action: String::from("mailbox_poll"),
+            author_key: String::default(),
+            signature: String::default(),
+            ..Default::default()
+        },
+        mailboxes,
+    })
+}
+
+/// Seal a signed message and drop it in the current mailbox of `friend`
+pub fn deliver_to_mailbox(message: &MessageData, friend: &Friend, now: u64) -> Result<Packet, PacketGenerationError> {
+    let mailbox = mailbox_id(&friend.shared_key, &friend.public_ed, mailbox_epoch(now)).map_err(|e| PacketGenerationError::shared_key("shared_key").with_source(e))?;
+
+    Ok(Packet::MailboxDeliver(MailboxDeliverData {
+        headers: PacketHeader {
+            action: String::from("mailbox_deliver"),
+            author_key: String::default(),
+            signature: String::default(),
+            ..Default::default()
+        },
+        mailbox,
+        payload: seal_payload(message, &friend.public_published)?,
+    }))
+}
+
+/// Open a message taken from one of our mailboxes and check that it was addressed to us
+pub fn open_mailbox_delivery(packet: &Packet, own_public_ed: &str, private_published_keys: &[String], revoked_keys: &[String]) -> Result<MessageData, PacketReadingError> {
+    let Packet::MailboxDeliver(data) = packet else {
+        return Err(PacketReadingError::unexpected(packet));
+    };
+
+    let message = unseal_payload(&data.payload, private_published_keys, revoked_keys)?;
+    if !same_ed_key(&message.recipient, own_public_ed) {
+        return Err(PacketReadingError::data("recipient").with_action(&data.headers.action));
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encryption::keys::{generate_ed_keys, generate_shared_key, generate_x_keys};
+
+    use super::{mailbox_id, MAILBOX_PERIOD, mailbox_epoch};
+
+    #[test]
+    fn test_mailbox_rotation() {
+        let (_, alice) = generate_ed_keys();
+        let (_, bob) = generate_ed_keys();
+        let (alice_x, alice_public_x) = generate_x_keys();
+        let (bob_x, bob_public_x) = generate_x_keys();
+        let alice_shared = generate_shared_key(&alice_x, &bob_public_x).expect("Unable to generate shared key");
+        let bob_shared = generate_shared_key(&bob_x, &alice_public_x).expect("Unable to generate shared key");
+
+        let epoch = mailbox_epoch(1_700_000_000);
+        let to_bob = mailbox_id(&alice_shared, &bob, epoch).expect("Unable to derive mailbox");
+        assert_eq!(to_bob, mailbox_id(&bob_shared, &bob, epoch).expect("Unable to derive mailbox"));
+        assert_ne!(to_bob, mailbox_id(&alice_shared, &alice, epoch).expect("Unable to derive mailbox"));
+        assert_ne!(to_bob, mailbox_id(&alice_shared, &bob, mailbox_epoch(1_700_000_000 + MAILBOX_PERIOD)).expect("Unable to derive mailbox"));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{attachments::{encrypt_message_payload, MessagePayload}, config::{Friend, UserInformation}, encryption::{deniable::message_authentication, pinning::ensure_can_send, pseudonym::{derive_relay_keys, derive_relay_published}, signature::{compact_author_key, sign_packet}}, packets::{requests::PendingRequests, FriendRequestData, LoginData, MessageData, Packet, PacketGenerationError, PacketHeader, RegisterData}};
+
+/// Message to `friend`, the payload is encrypted with their shared key and padded following the
+/// conversation setting
+#[derive(Clone, Copy)]
+pub struct MessageContext<'a> {
+    pub friend: &'a Friend,
+    pub payload: &'a MessagePayload,
+    /// Date in ISO 8601 format
+    pub sent_at: &'a str,
+}
+
+/// Friend request to `recipient`. `author_x` is the public x25519 key generated for this friend,
+/// `kem_ciphertext` the KEM encapsulation of an hybrid key agreement, empty otherwise.
+#[derive(Clone, Copy)]
+pub struct FriendRequestContext<'a> {
+    pub recipient: &'a str,
+    pub author_x: &'a str,
+    pub capabilities: &'a [String],
+    pub kem_ciphertext: &'a str,
+}
+
+/// Login or register on `relay` under its pseudonym, see [`crate::encryption::pseudonym`]. The
+/// certificate given to friends is created by [`crate::encryption::pseudonym::relay_pseudonym`].
+#[derive(Clone, Copy)]
+pub struct LoginContext<'a> {
+    pub relay: &'a str,
+    pub capabilities: &'a [String],
+}
+
+/// Register on `relay` under its pseudonym, `kem_published` is the published KEM key, empty for
+/// classic-only clients
+#[derive(Clone, Copy)]
+pub struct RegisterContext<'a> {
+    pub relay: &'a str,
+    pub kem_published: &'a str,
+}
+
+/// Packets written by clients, built from the keys of the user and signed with their identity key,
+/// or with a key derived from it for packets sent under a relay pseudonym
+pub trait ClientPacketGeneration {
+    /// Data given along with the user keys
+    type Context<'a>: Copy;
+
+    /// Build the packet with its headers filled, without signing it
+    fn build(user: &UserInformation, context: Self::Context<'_>) -> Result<Packet, PacketGenerationError>;
+
+    /// Private key the packet is signed with, the identity key by default
+    fn signing_key(user: &UserInformation, _context: Self::Context<'_>) -> Result<String, PacketGenerationError> {
+        Ok(user.author_private_ed.to_string())
+    }
+
+    fn generate(user: &UserInformation, context: Self::Context<'_>) -> Result<Packet, PacketGenerationError> {
+        let mut packet = Self::build(user, context)?;
+        sign_packet(&mut packet, &Self::signing_key(user, context)?)?;
+        Ok(packet)
+    }
+
+    /// Same as [`ClientPacketGeneration::generate`], the packet being given a request id tracked by
+    /// `pending` before it is signed
+    fn generate_tracked(user: &UserInformation, context: Self::Context<'_>, pending: &mut PendingRequests) -> Result<Packet, PacketGenerationError> {
+        let mut packet = Self::build(user, context)?;
+        pending.track(&mut packet);
+        sign_packet(&mut packet, &Self::signing_key(user, context)?)?;
+        Ok(packet)
+    }
+}
+
+fn headers(action: &str, user: &UserInformation) -> PacketHeader {
+    PacketHeader {
+        action: action.to_string(),
+        author_key: user.author_public_ed.to_string(),
+        signature: String::default(),
+        ..Default::default()
+    }
+}
+
+/// Same packet as [`crate::encryption::pseudonym::login_packet`]: written with the login key of the
+/// relay and advertising the given capabilities, see [`crate::packets::protocol::local_capabilities`]
+impl ClientPacketGeneration for LoginData {
+    type Context<'a> = LoginContext<'a>;
+
+    fn build(user: &UserInformation, context: LoginContext<'_>) -> Result<Packet, PacketGenerationError> {
+        let (_, login_key) = derive_relay_keys(user.author_private_ed, context.relay)?;
+
+        Ok(Packet::Login(LoginData {
+            headers: PacketHeader { author_key: login_key, ..headers("login", user) },
+            capabilities: context.capabilities.to_vec(),
+        }))
+    }
+
+    fn signing_key(user: &UserInformation, context: LoginContext<'_>) -> Result<String, PacketGenerationError> {
+        Ok(derive_relay_keys(user.author_private_ed, context.relay)?.0)
+    }
+}
+
+/// Same packet as [`crate::encryption::pseudonym::register_packet`]: written with the login key of
+/// the relay and giving it the published key derived for it
+impl ClientPacketGeneration for RegisterData {
+    type Context<'a> = RegisterContext<'a>;
+
+    fn build(user: &UserInformation, context: RegisterContext<'_>) -> Result<Packet, PacketGenerationError> {
+        let (_, login_key) = derive_relay_keys(user.author_private_ed, context.relay)?;
+        let (_, published) = derive_relay_published(user.author_private_published, context.relay)?;
+
+        Ok(Packet::Register(RegisterData {
+            headers: PacketHeader { author_key: login_key, ..headers("register", user) },
+            author_published: published,
+            author_kem_published: context.kem_published.to_string(),
+        }))
+    }
+
+    fn signing_key(user: &UserInformation, context: RegisterContext<'_>) -> Result<String, PacketGenerationError> {
+        Ok(derive_relay_keys(user.author_private_ed, context.relay)?.0)
+    }
+}
+
+/// Messages to a friend whose key changed and hasn't been accepted yet are refused. The author key
+/// is written in its compact form if the friend supports it.
+impl ClientPacketGeneration for MessageData {
+    type Context<'a> = MessageContext<'a>;
+
+    fn build(user: &UserInformation, context: MessageContext<'_>) -> Result<Packet, PacketGenerationError> {
+        let friend = context.friend;
+        ensure_can_send(friend).map_err(|e| PacketGenerationError::from(e).with_action("message"))?;
+
+        let mut message = MessageData {
+            headers: headers("message", user),
+            recipient: friend.public_ed.clone(),
+            sent_at: context.sent_at.to_string(),
+            content: encrypt_message_payload(context.payload, &friend.shared_key, friend.padding, &friend.capabilities)?,
+            ..Default::default()
+        };
+        compact_author_key(&mut message.headers, &friend.capabilities);
+        message.mac = message_authentication(&message, friend)?;
+        Ok(Packet::Message(message))
+    }
+}
+
+impl ClientPacketGeneration for FriendRequestData {
+    type Context<'a> = FriendRequestContext<'a>;
+
+    fn build(user: &UserInformation, context: FriendRequestContext<'_>) -> Result<Packet, PacketGenerationError> {
+        Ok(Packet::FriendRequest(FriendRequestData {
+            headers: headers("friend_request", user),
+            recipient: context.recipient.to_string(),
+            author_x: context.author_x.to_string(),
+            capabilities: context.capabilities.to_vec(),
+            kem_ciphertext: context.kem_ciphertext.to_string(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{attachments::{decrypt_message_payload, MessagePayload}, config::{Friend, KeyKind, MessageAuthentication, PinnedKey, UserInformation}, encryption::{deniable::verify_message, keys::{ed_public_pem_to_raw, generate_ed_keys, generate_shared_key, generate_x_keys}, pseudonym::{derive_relay_keys, derive_relay_published}, signature::verify_packet_signature}, packets::{protocol::COMPACT_KEYS, requests::PendingRequests, FriendRequestData, LoginData, MessageData, Packet, PacketGenerationError, RegisterData}};
+
+    use super::{ClientPacketGeneration, FriendRequestContext, LoginContext, MessageContext, RegisterContext};
+
+    #[test]
+    fn test_client_packet_generation() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let (private_published, public_published) = generate_x_keys();
+        let user = UserInformation {
+            author_public_ed: &public_ed,
+            author_private_ed: &private_ed,
+            author_public_published: &public_published,
+            author_private_published: &private_published,
+            username: "alice",
+            profile_picture: "None",
+        };
+
+        // login and register are written under the relay pseudonym, never with the identity keys
+        let capabilities = [String::from("groups")];
+        let (_, login_key) = derive_relay_keys(&private_ed, "relay.example").expect("Unable to derive login keys");
+        let Ok(login) = LoginData::generate(&user, LoginContext { relay: "relay.example", capabilities: &capabilities }) else {
+            panic!("Unable to generate login packet");
+        };
+        assert!(verify_packet_signature(&login, &[]).is_ok());
+        assert!(matches!(&login, Packet::Login(data) if data.capabilities == capabilities && data.headers.author_key == login_key));
+
+        let (_, relay_published) = derive_relay_published(&private_published, "relay.example").expect("Unable to derive published keys");
+        let Ok(register) = RegisterData::generate(&user, RegisterContext { relay: "relay.example", kem_published: "" }) else {
+            panic!("Unable to generate register packet");
+        };
+        assert!(verify_packet_signature(&register, &[]).is_ok());
+        let Packet::Register(register) = register else {
+            panic!("Register generated as another packet");
+        };
+        assert_eq!(register.headers.author_key, login_key);
+        assert_eq!(register.author_published, relay_published);
+        assert_ne!(register.author_published, public_published);
+
+        let (private_x, public_x) = generate_x_keys();
+        let (_, friend_public_ed) = generate_ed_keys();
+        let friend = Friend {
+            public_ed: friend_public_ed.clone(),
+            shared_key: generate_shared_key(&private_x, &public_published).expect("Unable to generate shared key"),
+            authentication: MessageAuthentication::Deniable,
+            ..Default::default()
+        };
+        let payload = MessagePayload { text: String::from("hello"), attachments: Vec::new() };
+        let context = MessageContext { friend: &friend, payload: &payload, sent_at: "2025-01-01T00:00:00Z" };
+
+        let mut pending = PendingRequests::new();
+        let Ok(message) = MessageData::generate_tracked(&user, context, &mut pending) else {
+            panic!("Unable to generate message packet");
+        };
+        assert!(pending.is_pending(&message.headers().request_id));
+        // the friend reads the message with us as its own friend
+        let sender = Friend { public_ed: public_ed.clone(), ..friend.clone() };
+        assert!(verify_message(&message, &sender, &[]).is_ok());
+        let Packet::Message(data) = &message else {
+            panic!("Message generated as another packet");
+        };
+        assert_eq!(decrypt_message_payload(&data.content, &friend.shared_key, &friend.capabilities).ok().as_ref(), Some(&payload));
+        assert_eq!(data.headers.author_key, public_ed);
+
+        // the compact key form is only used with friends supporting it
+        let compact_friend = Friend { capabilities: vec![COMPACT_KEYS.to_string()], ..friend.clone() };
+        let context = MessageContext { friend: &compact_friend, payload: &payload, sent_at: "2025-01-01T00:00:00Z" };
+        let Ok(Packet::Message(compact)) = MessageData::generate(&user, context) else {
+            panic!("Unable to generate message packet");
+        };
+        assert_eq!(compact.headers.author_key, ed_public_pem_to_raw(&public_ed).expect("Unable to convert key"));
+        assert!(verify_message(&Packet::Message(compact), &sender, &[]).is_ok());
+
+        let context = FriendRequestContext { recipient: &friend_public_ed, author_x: &public_x, capabilities: &capabilities, kem_ciphertext: "" };
+        let Ok(request) = FriendRequestData::generate(&user, context) else {
+            panic!("Unable to generate friend request packet");
+        };
+        assert!(verify_packet_signature(&request, &[]).is_ok());
+
+        let changed = Friend { pending_key_change: Some(PinnedKey { kind: KeyKind::Identity, key: public_x.clone(), changed_at: 0 }), ..friend.clone() };
+        let context = MessageContext { friend: &changed, payload: &payload, sent_at: "2025-01-01T00:00:00Z" };
+        assert!(matches!(MessageData::generate(&user, context), Err(PacketGenerationError::KeyChanged { action, .. }) if action == "message"));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::packets::{limits::{check_packet_size, PacketLimits}, protocol::{ENCODING_CBOR, MANDATORY_PREFIX}, Packet, PacketGenerationError, PacketReadingError};
+
+/// Wire format of the packets of a connection. Signature payloads are built from the decoded
+/// fields, so a packet can be transcoded from one format to the other without breaking its
+/// signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// Binary CBOR, only available with the `cbor` feature
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+/// Encoding to use on a connection once the capabilities of the peer are known, JSON stays the
+/// default for peers that didn't advertise anything else
+pub fn negotiate_encoding(remote_capabilities: &[String]) -> Encoding {
+    let cbor = remote_capabilities.iter().any(|capability| capability.trim_start_matches(MANDATORY_PREFIX) == ENCODING_CBOR);
+
+    match cbor {
+        #[cfg(feature = "cbor")]
+        true => Encoding::Cbor,
+        _ => Encoding::Json,
+    }
+}
+
+impl Packet {
+    /// Encode the packet in the given wire format
+    pub fn encode(&self, encoding: Encoding) -> Result<Vec<u8>, PacketGenerationError> {
+        match encoding {
+            Encoding::Json => Ok(self.to_json()?.into_bytes()),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => {
+                let mut encoded = Vec::new();
+                ciborium::into_writer(self, &mut encoded).map_err(|e| PacketGenerationError::Encoding { action: self.headers().action.clone(), source: Box::new(e) })?;
+                Ok(encoded)
+            }
+        }
+    }
+
+    /// Decode a packet written in the given wire format with the default limits, without verifying
+    /// its signature
+    pub fn decode(data: &[u8], encoding: Encoding) -> Result<Packet, PacketReadingError> {
+        Packet::decode_with_limits(data, encoding, &PacketLimits::DEFAULT)
+    }
+
+    /// Same as [`Packet::decode`] with the given limits. The size is checked before anything is
+    /// parsed, CBOR strings are read from the input by chunks so a length announced in a header
+    /// can't make the parser allocate more than the size of the packet.
+    pub fn decode_with_limits(data: &[u8], encoding: Encoding, limits: &PacketLimits) -> Result<Packet, PacketReadingError> {
+        check_packet_size(data.len(), limits)?;
+
+        match encoding {
+            Encoding::Json => Packet::from_value_with_limits(serde_json::from_slice(data)?, limits),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => {
+                let value = ciborium::de::from_reader_with_recursion_limit(data, limits.max_depth + 1).map_err(|e| PacketReadingError::data("packet").with_source(e))?;
+                Packet::from_value_with_limits(value, limits)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "cbor"))]
+mod test {
+    use crate::{encryption::{keys::generate_ed_keys, signature::{sign_packet, verify_packet_signature}}, packets::{MessageData, Packet, PacketHeader}};
+
+    use super::{negotiate_encoding, Encoding};
+
+    #[test]
+    fn test_cbor_transcoding_keeps_signature() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let mut packet = Packet::Message(MessageData {
+            headers: PacketHeader { action: String::from("message"), author_key: public_ed, ..Default::default() },
+            recipient: String::from("recipient"),
+            content: String::from("hello"),
+            ..Default::default()
+        });
+        sign_packet(&mut packet, &private_ed).expect("Unable to sign packet");
+
+        let cbor = packet.encode(Encoding::Cbor).expect("Unable to encode packet");
+        assert!(cbor.len() < packet.encode(Encoding::Json).expect("Unable to encode packet").len());
+
+        let Ok(decoded) = Packet::decode(&cbor, Encoding::Cbor) else {
+            panic!("Unable to decode packet");
+        };
+        assert!(verify_packet_signature(&decoded, &[]).is_ok());
+
+        let json = decoded.encode(Encoding::Json).expect("Unable to transcode packet");
+        let Ok(transcoded) = Packet::decode(&json, Encoding::Json) else {
+            panic!("Unable to decode transcoded packet");
+        };
+        assert!(verify_packet_signature(&transcoded, &[]).is_ok());
+
+        assert_eq!(negotiate_encoding(&[String::from("encoding_cbor")]), Encoding::Cbor);
+        assert_eq!(negotiate_encoding(&[]), Encoding::Json);
+    }
+}
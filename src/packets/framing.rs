@@ -0,0 +1,256 @@
+use std::{fmt::Display, io::{self, Read, Write}};
+
+use crate::packets::{encoding::Encoding, limits::PacketLimits, Packet, PacketGenerationError, PacketReadingError};
+
+/// Size of the length put in front of every frame (big endian u32)
+const LENGTH_SIZE: usize = 4;
+
+#[derive(Debug)]
+pub enum FramingError {
+    Io(io::Error),
+    /// Frame announced or produced larger than the limit of the connection
+    Oversized { size: usize, max: usize },
+    /// Stream closed in the middle of a frame
+    Truncated { expected: usize, received: usize },
+    Reading(PacketReadingError),
+    Generation(PacketGenerationError),
+}
+
+impl Display for FramingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FramingError::Io(e) => {
+                write!(f, "IO error: {}", e)
+            }
+            FramingError::Oversized { size, max } => {
+                write!(f, "Frame of {size} bytes exceeds the limit of {max} bytes")
+            }
+            FramingError::Truncated { expected, received } => {
+                write!(f, "Stream closed after {received} of {expected} bytes of a frame")
+            }
+            FramingError::Reading(e) => {
+                write!(f, "Unable to read packet: {}", e)
+            }
+            FramingError::Generation(e) => {
+                write!(f, "Unable to write packet: {}", e)
+            }
+        }
+    }
+}
+impl std::error::Error for FramingError {}
+
+/// Length-prefixed framing of packets on stream transports (TCP, unix sockets...): each packet is
+/// encoded with `encoding` and preceded by its length as a big endian u32.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketCodec {
+    pub encoding: Encoding,
+    /// Limits of the packets read on this connection, frames are bounded by
+    /// [`PacketLimits::max_packet_size`] both ways
+    pub limits: PacketLimits,
+}
+
+impl Default for PacketCodec {
+    fn default() -> Self {
+        Self { encoding: Encoding::default(), limits: PacketLimits::DEFAULT }
+    }
+}
+
+impl PacketCodec {
+    pub fn new(encoding: Encoding, limits: PacketLimits) -> Self {
+        Self { encoding, limits }
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), FramingError> {
+        let max = self.limits.max_packet_size;
+        if size > max {
+            return Err(FramingError::Oversized { size, max });
+        }
+        Ok(())
+    }
+
+    /// Encode a packet with its length prefix
+    pub fn frame(&self, packet: &Packet) -> Result<Vec<u8>, FramingError> {
+        let payload = packet.encode(self.encoding)?;
+        self.check_size(payload.len())?;
+
+        let mut frame = Vec::with_capacity(LENGTH_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend(payload);
+        Ok(frame)
+    }
+
+    pub fn write_packet(&self, writer: &mut impl Write, packet: &Packet) -> Result<(), FramingError> {
+        writer.write_all(&self.frame(packet)?)?;
+        Ok(())
+    }
+
+    /// Read the next packet of the stream, without verifying its signature.
+    ///
+    /// Returns `None` when the stream is closed between two frames.
+    pub fn read_packet(&self, reader: &mut impl Read) -> Result<Option<Packet>, FramingError> {
+        let mut length = [0u8; LENGTH_SIZE];
+        match read_full(reader, &mut length)? {
+            0 => return Ok(None),
+            LENGTH_SIZE => {}
+            received => return Err(FramingError::Truncated { expected: LENGTH_SIZE, received }),
+        }
+
+        let size = u32::from_be_bytes(length) as usize;
+        self.check_size(size)?;
+
+        let mut payload = vec![0u8; size];
+        let received = read_full(reader, &mut payload)?;
+        if received < size {
+            return Err(FramingError::Truncated { expected: size, received });
+        }
+        Ok(Some(Packet::decode_with_limits(&payload, self.encoding, &self.limits)?))
+    }
+}
+
+/// Fill `buffer` unless the stream ends first, returns the number of bytes read
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut received = 0;
+    while received < buffer.len() {
+        match reader.read(&mut buffer[received..]) {
+            Ok(0) => break,
+            Ok(read) => received += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(received)
+}
+
+#[cfg(feature = "tokio")]
+mod codec {
+    use bytes::{Buf, BufMut, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{FramingError, PacketCodec, LENGTH_SIZE};
+    use crate::packets::Packet;
+
+    impl Decoder for PacketCodec {
+        type Item = Packet;
+        type Error = FramingError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, FramingError> {
+            let Some(length) = src.get(..LENGTH_SIZE) else {
+                return Ok(None);
+            };
+            let size = u32::from_be_bytes(length.try_into().expect("Length prefix is 4 bytes")) as usize;
+            self.check_size(size)?;
+
+            if src.len() < LENGTH_SIZE + size {
+                src.reserve(LENGTH_SIZE + size - src.len());
+                return Ok(None);
+            }
+
+            src.advance(LENGTH_SIZE);
+            let payload = src.split_to(size);
+            Ok(Some(Packet::decode_with_limits(&payload, self.encoding, &self.limits)?))
+        }
+
+        fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, FramingError> {
+            match self.decode(src)? {
+                Some(packet) => Ok(Some(packet)),
+                None if src.is_empty() => Ok(None),
+                None => {
+                    let expected = src.get(..LENGTH_SIZE)
+                        .map(|length| LENGTH_SIZE + u32::from_be_bytes(length.try_into().expect("Length prefix is 4 bytes")) as usize)
+                        .unwrap_or(LENGTH_SIZE);
+                    Err(FramingError::Truncated { expected, received: src.len() })
+                }
+            }
+        }
+    }
+
+    impl Encoder<&Packet> for PacketCodec {
+        type Error = FramingError;
+
+        fn encode(&mut self, packet: &Packet, dst: &mut BytesMut) -> Result<(), FramingError> {
+            let payload = packet.encode(self.encoding)?;
+            self.check_size(payload.len())?;
+
+            dst.reserve(LENGTH_SIZE + payload.len());
+            dst.put_u32(payload.len() as u32);
+            dst.extend_from_slice(&payload);
+            Ok(())
+        }
+    }
+}
+
+impl From<io::Error> for FramingError {
+    fn from(err: io::Error) -> Self {
+        FramingError::Io(err)
+    }
+}
+
+impl From<PacketReadingError> for FramingError {
+    fn from(err: PacketReadingError) -> Self {
+        FramingError::Reading(err)
+    }
+}
+
+impl From<PacketGenerationError> for FramingError {
+    fn from(err: PacketGenerationError) -> Self {
+        FramingError::Generation(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::packets::{encoding::Encoding, limits::PacketLimits, ErrorCode, ErrorData, Packet, PacketHeader};
+
+    use super::{FramingError, PacketCodec};
+
+    #[test]
+    fn test_framing() {
+        let codec = PacketCodec::default();
+        let packet = Packet::Error(ErrorData {
+            headers: PacketHeader { action: String::from("error"), ..Default::default() },
+            code: ErrorCode::UnknownRecipient,
+            message: String::from("unknown recipient"),
+        });
+
+        let mut stream = Vec::new();
+        codec.write_packet(&mut stream, &packet).expect("Unable to write packet");
+        codec.write_packet(&mut stream, &packet).expect("Unable to write packet");
+
+        let mut reader = stream.as_slice();
+        assert!(matches!(codec.read_packet(&mut reader), Ok(Some(Packet::Error(_)))));
+        assert!(matches!(codec.read_packet(&mut reader), Ok(Some(Packet::Error(_)))));
+        assert!(matches!(codec.read_packet(&mut reader), Ok(None)));
+
+        let mut truncated = &stream[..stream.len() - 3];
+        assert!(codec.read_packet(&mut truncated).is_ok());
+        assert!(matches!(codec.read_packet(&mut truncated), Err(FramingError::Truncated { .. })));
+
+        let small = PacketCodec::new(Encoding::Json, PacketLimits { max_packet_size: 16, ..PacketLimits::DEFAULT });
+        assert!(matches!(small.read_packet(&mut stream.as_slice()), Err(FramingError::Oversized { max: 16, .. })));
+        assert!(matches!(small.frame(&packet), Err(FramingError::Oversized { .. })));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_tokio_codec() {
+        use bytes::BytesMut;
+        use tokio_util::codec::{Decoder, Encoder};
+
+        let mut codec = PacketCodec::default();
+        let packet = Packet::Error(ErrorData {
+            headers: PacketHeader { action: String::from("error"), ..Default::default() },
+            code: ErrorCode::UnknownRecipient,
+            message: String::from("unknown recipient"),
+        });
+
+        let mut buffer = BytesMut::new();
+        codec.encode(&packet, &mut buffer).expect("Unable to encode packet");
+        let mut partial = buffer.split_to(buffer.len() - 1);
+        assert!(matches!(codec.decode(&mut partial), Ok(None)));
+        assert!(matches!(codec.decode_eof(&mut partial.clone()), Err(FramingError::Truncated { .. })));
+
+        partial.unsplit(buffer);
+        assert!(matches!(codec.decode(&mut partial), Ok(Some(Packet::Error(_)))));
+        assert!(partial.is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use serde_json::Value;
+
+use crate::packets::PacketReadingError;
+
+/// Limits applied to every packet read, before its signature is verified. The default values fit
+/// the packets written by this crate, relays can tighten or loosen them per connection with
+/// [`crate::packets::framing::PacketCodec::limits`].
+///
+/// Only [`PacketLimits::max_packet_size`] bounds the parsing work: the other limits are checked
+/// once the packet has been parsed in a [`Value`], whose size stays proportional to the size of
+/// the encoded packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketLimits {
+    /// Size of the encoded packet
+    pub max_packet_size: usize,
+    /// Nesting depth of objects and arrays, the packet itself being at depth 1
+    pub max_depth: usize,
+    /// Number of items of an array
+    pub max_items: usize,
+    /// Length of the fields carrying a payload, see [`CONTENT_FIELDS`]
+    pub max_content_length: usize,
+    /// Length of the names, see [`NAME_FIELDS`]
+    pub max_name_length: usize,
+    /// Length of any other string (keys, signatures, identifiers...)
+    pub max_field_length: usize,
+}
+
+/// Fields allowed to reach [`PacketLimits::max_content_length`]
+pub const CONTENT_FIELDS: [&str; 6] = ["content", "message", "data", "payload", "sealed", "backup"];
+
+/// Fields limited to [`PacketLimits::max_name_length`]: group names and device ids
+pub const NAME_FIELDS: [&str; 2] = ["name", "device_id"];
+
+impl PacketLimits {
+    pub const DEFAULT: PacketLimits = PacketLimits {
+        max_packet_size: 1024 * 1024,
+        max_depth: 8,
+        max_items: 1024,
+        max_content_length: 768 * 1024,
+        max_name_length: 256,
+        max_field_length: 8 * 1024,
+    };
+}
+
+impl Default for PacketLimits {
+    fn default() -> Self {
+        PacketLimits::DEFAULT
+    }
+}
+
+fn exceeded(field: &str, length: usize, max: usize) -> PacketReadingError {
+    PacketReadingError::LimitExceeded { action: String::new(), field: field.to_string(), length, max }
+}
+
+/// Check the size of an encoded packet, it must be called before the packet is parsed
+pub fn check_packet_size(size: usize, limits: &PacketLimits) -> Result<(), PacketReadingError> {
+    let max = limits.max_packet_size;
+    if size > max {
+        return Err(exceeded("packet", size, max));
+    }
+    Ok(())
+}
+
+/// Check the depth, array sizes and string lengths of a parsed packet
+pub fn check_packet_fields(packet: &Value, limits: &PacketLimits) -> Result<(), PacketReadingError> {
+    check_value(packet, "packet", 1, limits)
+}
+
+fn check_value(value: &Value, field: &str, depth: usize, limits: &PacketLimits) -> Result<(), PacketReadingError> {
+    match value {
+        Value::String(string) => {
+            let max = if CONTENT_FIELDS.contains(&field) {
+                limits.max_content_length
+            } else if NAME_FIELDS.contains(&field) {
+                limits.max_name_length
+            } else {
+                limits.max_field_length
+            };
+            if string.len() > max {
+                return Err(exceeded(field, string.len(), max));
+            }
+        }
+        Value::Array(items) => {
+            if depth > limits.max_depth {
+                return Err(exceeded("depth", depth, limits.max_depth));
+            }
+            if items.len() > limits.max_items {
+                return Err(exceeded(field, items.len(), limits.max_items));
+            }
+            for item in items {
+                check_value(item, field, depth + 1, limits)?;
+            }
+        }
+        Value::Object(fields) => {
+            if depth > limits.max_depth {
+                return Err(exceeded("depth", depth, limits.max_depth));
+            }
+            for (name, value) in fields {
+                check_value(value, name, depth + 1, limits)?;
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::packets::{framing::{FramingError, PacketCodec}, LoginData, Packet, PacketHeader, PacketReadingError};
+
+    use super::{check_packet_fields, check_packet_size, PacketLimits};
+
+    #[test]
+    fn test_packet_limits() {
+        let limits = PacketLimits::DEFAULT;
+        assert!(check_packet_size(limits.max_packet_size, &limits).is_ok());
+        assert!(check_packet_size(limits.max_packet_size + 1, &limits).is_err());
+
+        let content = "a".repeat(limits.max_field_length + 1);
+        assert!(check_packet_fields(&json!({ "headers": { "action": "message" }, "content": content }), &limits).is_ok());
+        assert!(matches!(
+            check_packet_fields(&json!({ "headers": { "action": content } }), &limits),
+            Err(PacketReadingError::LimitExceeded { field, .. }) if field == "action"
+        ));
+
+        let mut nested = json!("leaf");
+        for _ in 0..=limits.max_depth {
+            nested = json!([nested]);
+        }
+        assert!(matches!(
+            check_packet_fields(&nested, &limits),
+            Err(PacketReadingError::LimitExceeded { field, .. }) if field == "depth"
+        ));
+
+        let name = "a".repeat(limits.max_name_length + 1);
+        assert!(check_packet_fields(&json!({ "name": &name[1..] }), &limits).is_ok());
+        assert!(matches!(
+            check_packet_fields(&json!({ "name": name }), &limits),
+            Err(PacketReadingError::LimitExceeded { field, max, .. }) if field == "name" && max == limits.max_name_length
+        ));
+
+        let items = vec!["groups"; limits.max_items + 1];
+        assert!(check_packet_fields(&json!({ "capabilities": &items[1..] }), &limits).is_ok());
+        assert!(matches!(
+            check_packet_fields(&json!({ "capabilities": items }), &limits),
+            Err(PacketReadingError::LimitExceeded { field, length, .. }) if field == "capabilities" && length == limits.max_items + 1
+        ));
+    }
+
+    #[test]
+    fn test_codec_limits() {
+        let packet = Packet::Login(LoginData {
+            headers: PacketHeader { action: String::from("login"), ..Default::default() },
+            capabilities: vec![String::from("groups"); 4],
+        });
+        let codec = PacketCodec::default();
+        let frame = codec.frame(&packet).expect("Unable to frame packet");
+        assert!(matches!(codec.read_packet(&mut frame.as_slice()), Ok(Some(Packet::Login(_)))));
+
+        let strict = PacketCodec { limits: PacketLimits { max_items: 2, ..PacketLimits::DEFAULT }, ..codec };
+        assert!(matches!(strict.read_packet(&mut frame.as_slice()), Err(FramingError::Reading(e)) if e.action() == Some("login")));
+        // the default limits are still used by the other readers
+        assert!(codec.read_packet(&mut frame.as_slice()).is_ok());
+    }
+}
@@ -1,121 +1,432 @@
-use std::{fmt::Display, fs};
+use std::fmt::Display;
 
 use ed25519_dalek::{ed25519::signature, pkcs8::{self, spki}};
-use serde::{Deserialize, Serialize};
+use serde::{de::{self, DeserializeOwned}, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 
-use crate::encryption::signature::verify_packet_signature;
+use crate::{devices::DeviceCertificate, encryption::{pinning::KeyChanged, revocation::RevocationCertificate, signature::verify_packet_signature}, packets::{limits::{check_packet_fields, check_packet_size, PacketLimits}, protocol::{check_version, PROTOCOL_VERSION}}};
 
-/// Differents types of packets, all new packets will be added here
-pub enum Packet {
-    Login(LoginData),
-    Message(MessageData),
-    FriendRequest(FriendRequestData),
-    RetrievePublished(RetrievePublishedData),
-    Register(RegisterData),
-    Announcement(AnnouncementData),
-    Error(ErrorData),
+pub mod protocol;
+pub mod encoding;
+pub mod framing;
+pub mod limits;
+pub mod requests;
+pub mod client;
+
+/// Declare every packet type along with its action. The enum, the headers accessors and the
+/// dispatch of [`Packet::from_value`] are generated from this single table.
+macro_rules! packets {
+    ($($variant:ident($data:ty) = $action:literal,)*) => {
+        /// Differents types of packets, all new packets will be added here
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        pub enum Packet {
+            $($variant($data),)*
+        }
+
+        /// Action of every packet type, as written in `headers.action`
+        pub const ACTIONS: &[&str] = &[$($action,)*];
+
+        impl Packet {
+            pub fn headers(&self) -> &PacketHeader {
+                match self {
+                    $(Packet::$variant(data) => &data.headers,)*
+                }
+            }
+
+            pub fn headers_mut(&mut self) -> &mut PacketHeader {
+                match self {
+                    $(Packet::$variant(data) => &mut data.headers,)*
+                }
+            }
+
+            /// Read the data of the packet type designated by `action`
+            fn parse_action(packet: Value, action: String) -> Result<Packet, PacketReadingError> {
+                match action.as_str() {
+                    $($action => Ok(Packet::$variant(parse_packet(packet, &action)?)),)*
+                    _ => Err(PacketReadingError::Type { action }),
+                }
+            }
+        }
+    };
+}
+
+packets! {
+    Login(LoginData) = "login",
+    Message(MessageData) = "message",
+    FriendRequest(FriendRequestData) = "friend_request",
+    RetrievePublished(RetrievePublishedData) = "retrieve_published",
+    Register(RegisterData) = "register",
+    Announcement(AnnouncementData) = "announcement",
+    Error(ErrorData) = "error",
+    PublishedRotation(PublishedRotationData) = "published_rotation",
+    Revoke(RevokeData) = "revoke",
+    DeviceList(DeviceListData) = "device_list",
+    DeviceLinkRequest(DeviceLinkRequestData) = "device_link_request",
+    DeviceLinkResponse(DeviceLinkResponseData) = "device_link_response",
+    GroupCreate(GroupCreateData) = "group_create",
+    GroupInvite(GroupInviteData) = "group_invite",
+    GroupLeave(GroupLeaveData) = "group_leave",
+    GroupKick(GroupKickData) = "group_kick",
+    SenderKey(SenderKeyData) = "sender_key",
+    GroupMessage(GroupMessageData) = "group_message",
+    BlobUpload(BlobChunkData) = "blob_upload",
+    BlobChunk(BlobChunkData) = "blob_chunk",
+    BlobDownload(BlobRequestData) = "blob_download",
+    BlobDelete(BlobRequestData) = "blob_delete",
+    RecoveryShare(RecoveryShareData) = "recovery_share",
+    RecoveryRequest(RecoveryRequestData) = "recovery_request",
+    RecoveryResponse(RecoveryResponseData) = "recovery_response",
+    SealedMessage(SealedMessageData) = "sealed_message",
+    DeliveryTokenRegister(DeliveryTokenRegisterData) = "delivery_token_register",
+    DeliveryToken(DeliveryTokenData) = "delivery_token",
+    MailboxDeliver(MailboxDeliverData) = "mailbox_deliver",
+    MailboxPoll(MailboxPollData) = "mailbox_poll",
+    Pseudonym(PseudonymData) = "pseudonym",
+    Ack(AckData) = "ack",
+    Nack(NackData) = "nack",
+}
+
+/// Underlying error of a [`PacketGenerationError`] or a [`PacketReadingError`]
+pub type ErrorSource = Box<dyn std::error::Error + Send + Sync>;
+
+/// Write the `(action, field)` context of an error when it is known
+fn write_context(f: &mut std::fmt::Formatter<'_>, action: &str, field: &str) -> std::fmt::Result {
+    match (action.is_empty(), field.is_empty()) {
+        (true, true) => Ok(()),
+        (false, true) => write!(f, " (action {action})"),
+        (true, false) => write!(f, " (field {field})"),
+        (false, false) => write!(f, " (action {action}, field {field})"),
+    }
 }
 
 #[derive(Debug)]
 pub enum PacketGenerationError {
-    SingingKey,
-    EDKey,
-    SharedKey,
-    PayloadSerialisation(serde_json::Error)
+    /// Invalid or unreadable private ed25519 key
+    SingingKey { action: String, field: String, source: Option<ErrorSource> },
+    /// Invalid or unreadable public ed25519 key
+    EDKey { action: String, field: String, source: Option<ErrorSource> },
+    /// Invalid shared key, or invalid x25519 key used to compute it
+    SharedKey { action: String, field: String, source: Option<ErrorSource> },
+    PayloadSerialisation { action: String, source: serde_json::Error },
+    /// Failure of a binary encoding of a packet, see [`encoding`]
+    Encoding { action: String, source: ErrorSource },
+    /// Recipient whose key changed, nothing is sent to them until the change is accepted
+    KeyChanged { action: String, change: KeyChanged },
+}
+
+/// `action` is the action of the packet being generated and `field` the key at fault, a packet
+/// field or the configuration entry it is read from (`me.private_ed_path`...), both are left empty
+/// when unknown.
+impl PacketGenerationError {
+    pub fn signing_key(field: &str) -> Self {
+        PacketGenerationError::SingingKey { action: String::new(), field: field.to_string(), source: None }
+    }
+
+    pub fn ed_key(field: &str) -> Self {
+        PacketGenerationError::EDKey { action: String::new(), field: field.to_string(), source: None }
+    }
+
+    pub fn shared_key(field: &str) -> Self {
+        PacketGenerationError::SharedKey { action: String::new(), field: field.to_string(), source: None }
+    }
+
+    /// Attach the underlying error to a key error
+    pub fn with_source(mut self, error: impl std::error::Error + Send + Sync + 'static) -> Self {
+        if let PacketGenerationError::SingingKey { source, .. } | PacketGenerationError::EDKey { source, .. } | PacketGenerationError::SharedKey { source, .. } = &mut self {
+            *source = Some(Box::new(error));
+        }
+        self
+    }
+
+    /// Set the action of the packet being generated, if it isn't known yet
+    pub fn with_action(mut self, packet_action: &str) -> Self {
+        match &mut self {
+            PacketGenerationError::SingingKey { action, .. }
+            | PacketGenerationError::EDKey { action, .. }
+            | PacketGenerationError::SharedKey { action, .. }
+            | PacketGenerationError::PayloadSerialisation { action, .. }
+            | PacketGenerationError::Encoding { action, .. }
+            | PacketGenerationError::KeyChanged { action, .. } if action.is_empty() => *action = packet_action.to_string(),
+            _ => {}
+        }
+        self
+    }
+
+    /// Action of the packet being generated, when known
+    pub fn action(&self) -> Option<&str> {
+        match self {
+            PacketGenerationError::SingingKey { action, .. }
+            | PacketGenerationError::EDKey { action, .. }
+            | PacketGenerationError::SharedKey { action, .. }
+            | PacketGenerationError::PayloadSerialisation { action, .. }
+            | PacketGenerationError::Encoding { action, .. }
+            | PacketGenerationError::KeyChanged { action, .. } if !action.is_empty() => Some(action),
+            _ => None,
+        }
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            PacketGenerationError::SingingKey { field, .. }
+            | PacketGenerationError::EDKey { field, .. }
+            | PacketGenerationError::SharedKey { field, .. } if !field.is_empty() => Some(field),
+            _ => None,
+        }
+    }
 }
 
 impl Display for PacketGenerationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            PacketGenerationError::EDKey => {
+            PacketGenerationError::EDKey { action, field, .. } => {
                 write!(f, "Invalid ED_25519 key provided")?;
-                Ok(())
+                write_context(f, action, field)
             }
-            PacketGenerationError::SharedKey => {
+            PacketGenerationError::SharedKey { action, field, .. } => {
                 write!(f, "Invalid shared key provided")?;
-                Ok(())
+                write_context(f, action, field)
             }
-            PacketGenerationError::SingingKey => {
+            PacketGenerationError::SingingKey { action, field, .. } => {
                 write!(f, "Invalid signing (private ED_25519) key provided")?;
-                Ok(())
+                write_context(f, action, field)
             }
-            PacketGenerationError::PayloadSerialisation(e) => {
-                write!(f, "{e}")?;
-                Ok(())
+            PacketGenerationError::PayloadSerialisation { action, .. } => {
+                write!(f, "Unable to serialise payload")?;
+                write_context(f, action, "")
+            }
+            PacketGenerationError::Encoding { action, .. } => {
+                write!(f, "Unable to encode packet")?;
+                write_context(f, action, "")
+            }
+            PacketGenerationError::KeyChanged { action, .. } => {
+                write!(f, "Recipient key changed and must be accepted first")?;
+                write_context(f, action, "")
             }
         }
     }
 }
 
+impl std::error::Error for PacketGenerationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PacketGenerationError::SingingKey { source, .. } | PacketGenerationError::EDKey { source, .. } | PacketGenerationError::SharedKey { source, .. } => {
+                source.as_deref().map(|source| source as _)
+            }
+            PacketGenerationError::PayloadSerialisation { source, .. } => Some(source),
+            PacketGenerationError::Encoding { source, .. } => Some(source.as_ref()),
+            PacketGenerationError::KeyChanged { change, .. } => Some(change),
+        }
+    }
+}
+
+impl From<KeyChanged> for PacketGenerationError {
+    fn from(value: KeyChanged) -> Self {
+        PacketGenerationError::KeyChanged { action: String::new(), change: value }
+    }
+}
+
 impl From<serde_json::Error> for PacketGenerationError {
     fn from(value: serde_json::Error) -> Self {
-        PacketGenerationError::PayloadSerialisation(value)
+        PacketGenerationError::PayloadSerialisation { action: String::new(), source: value }
     }
 }
 
 impl From<pkcs8::Error> for PacketGenerationError {
-    fn from(_: pkcs8::Error) -> Self {
-        PacketGenerationError::SingingKey
+    fn from(value: pkcs8::Error) -> Self {
+        PacketGenerationError::SingingKey { action: String::new(), field: String::new(), source: Some(Box::new(value)) }
     }
 }
 
+/// `action` is the action of the packet being read and `field` the path of the field at fault
+/// (`headers.author_key`, `devices[0].signature`...), both are left empty when unknown.
+#[derive(Debug)]
 pub enum PacketReadingError {
-    Signature,
-    Key,
-    Type,
-    Data
+    /// Invalid signature of the packet, or of a certificate it carries
+    Signature { action: String, source: Option<signature::Error> },
+    /// Unreadable key, or payload encrypted with another key
+    Key { action: String, field: String, source: Option<ErrorSource> },
+    /// Unknown action, or packet of another type than expected
+    Type { action: String },
+    /// Missing or malformed data
+    Data { action: String, field: String, source: Option<ErrorSource> },
+    /// Packet written by a revoked key, `field` is the key found in the revocation list
+    Revoked { action: String, field: String },
+    /// Protocol version or mandatory capability not supported by this client
+    Unsupported { action: String, feature: String },
+    /// Packet or one of its fields is larger than the [`limits`] allow, checked before any
+    /// signature verification
+    LimitExceeded { action: String, field: String, length: usize, max: usize },
+}
+
+impl PacketReadingError {
+    pub fn key(field: &str) -> Self {
+        PacketReadingError::Key { action: String::new(), field: field.to_string(), source: None }
+    }
+
+    pub fn data(field: &str) -> Self {
+        PacketReadingError::Data { action: String::new(), field: field.to_string(), source: None }
+    }
+
+    /// Error for a packet of another type than the one expected
+    pub fn unexpected(packet: &Packet) -> Self {
+        PacketReadingError::Type { action: packet.headers().action.clone() }
+    }
+
+    /// Attach the underlying error to a [`PacketReadingError::Key`] or a [`PacketReadingError::Data`]
+    pub fn with_source(mut self, error: impl std::error::Error + Send + Sync + 'static) -> Self {
+        if let PacketReadingError::Key { source, .. } | PacketReadingError::Data { source, .. } = &mut self {
+            *source = Some(Box::new(error));
+        }
+        self
+    }
+
+    /// Set the action of the packet the error comes from, if it isn't known yet
+    pub fn with_action(mut self, packet_action: &str) -> Self {
+        match &mut self {
+            PacketReadingError::Signature { action, .. }
+            | PacketReadingError::Key { action, .. }
+            | PacketReadingError::Type { action }
+            | PacketReadingError::Data { action, .. }
+            | PacketReadingError::Revoked { action, .. }
+            | PacketReadingError::Unsupported { action, .. }
+            | PacketReadingError::LimitExceeded { action, .. } if action.is_empty() => *action = packet_action.to_string(),
+            _ => {}
+        }
+        self
+    }
+
+    /// Set the path of the field at fault, replacing the one given by a lower level helper
+    pub fn with_field(mut self, path: &str) -> Self {
+        match &mut self {
+            PacketReadingError::Key { field, .. } | PacketReadingError::Data { field, .. } | PacketReadingError::Revoked { field, .. } => *field = path.to_string(),
+            _ => {}
+        }
+        self
+    }
+
+    pub fn action(&self) -> Option<&str> {
+        match self {
+            PacketReadingError::Signature { action, .. }
+            | PacketReadingError::Key { action, .. }
+            | PacketReadingError::Type { action }
+            | PacketReadingError::Data { action, .. }
+            | PacketReadingError::Revoked { action, .. }
+            | PacketReadingError::Unsupported { action, .. }
+            | PacketReadingError::LimitExceeded { action, .. } if !action.is_empty() => Some(action),
+            _ => None,
+        }
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            PacketReadingError::Key { field, .. }
+            | PacketReadingError::Data { field, .. }
+            | PacketReadingError::Revoked { field, .. }
+            | PacketReadingError::LimitExceeded { field, .. } if !field.is_empty() => Some(field),
+            _ => None,
+        }
+    }
 }
 
 impl Display for PacketReadingError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            PacketReadingError::Signature => {
+            PacketReadingError::Signature { action, .. } => {
                 write!(f, "Packet has an invalid signature")?;
-                Ok(())
+                write_context(f, action, "")
             }
-            PacketReadingError::Key => {
-                write!(f, "Unable to read payload, invalid shared key provided")?;
-                Ok(())
+            PacketReadingError::Key { action, field, .. } => {
+                write!(f, "Unable to read payload, invalid key provided")?;
+                write_context(f, action, field)
             }
-            PacketReadingError::Type => {
+            PacketReadingError::Type { action } => {
                 write!(f, "Invalid format")?;
-                Ok(())
+                write_context(f, action, "")
             }
-            PacketReadingError::Data => {
+            PacketReadingError::Data { action, field, .. } => {
                 write!(f, "Missing data in the packet")?;
-                Ok(())
+                write_context(f, action, field)
+            }
+            PacketReadingError::Revoked { action, field } => {
+                write!(f, "Packet author key has been revoked")?;
+                write_context(f, action, field)
+            }
+            PacketReadingError::Unsupported { action, feature } => {
+                write!(f, "Unsupported protocol feature: {feature}")?;
+                write_context(f, action, "")
+            }
+            PacketReadingError::LimitExceeded { action, field, length, max } => {
+                write!(f, "Packet {field} is too large ({length} > {max})")?;
+                write_context(f, action, "")
             }
         }
     }
 }
 
+impl std::error::Error for PacketReadingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PacketReadingError::Signature { source, .. } => source.as_ref().map(|source| source as _),
+            PacketReadingError::Key { source, .. } | PacketReadingError::Data { source, .. } => source.as_deref().map(|source| source as _),
+            _ => None,
+        }
+    }
+}
 
 impl From<serde_json::Error> for PacketReadingError {
-    fn from(_: serde_json::Error) -> Self {
-        PacketReadingError::Data
+    fn from(value: serde_json::Error) -> Self {
+        PacketReadingError::Data { action: String::new(), field: String::new(), source: Some(Box::new(value)) }
     }
 }
 
-impl From<spki::Error> for PacketReadingError {
-    fn from(_: spki::Error) -> Self {
-        PacketReadingError::Key
+impl From<serde_path_to_error::Error<serde_json::Error>> for PacketReadingError {
+    fn from(value: serde_path_to_error::Error<serde_json::Error>) -> Self {
+        let field = value.path().to_string();
+        PacketReadingError::Data { action: String::new(), field, source: Some(Box::new(value.into_inner())) }
     }
 }
 
+impl From<spki::Error> for PacketReadingError {
+    fn from(value: spki::Error) -> Self {
+        PacketReadingError::Key { action: String::new(), field: String::new(), source: Some(Box::new(value)) }
+    }
+}
 
 impl From<signature::Error> for PacketReadingError {
-    fn from(_: signature::Error) -> Self {
-        PacketReadingError::Signature
+    fn from(value: signature::Error) -> Self {
+        PacketReadingError::Signature { action: String::new(), source: Some(value) }
     }
 }
 
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// `version` is the protocol version the packet is written in, packets sent before it was introduced
+/// don't have it and are read as version 0.
+///
+/// `request_id` is a UUID chosen by the client, the relay echoes it in the [`AckData`], [`NackData`] or
+/// [`ErrorData`] answering the packet, see [`requests`]. It is left out of the json when empty, and
+/// cleared when reading a version 0 packet whose signature doesn't cover it.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PacketHeader {
     pub action: String,
     pub author_key: String,
-    pub signature: String
+    pub signature: String,
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub request_id: String,
+}
+
+impl Default for PacketHeader {
+    fn default() -> Self {
+        Self {
+            action: String::default(),
+            author_key: String::default(),
+            signature: String::default(),
+            version: PROTOCOL_VERSION,
+            request_id: String::default(),
+        }
+    }
 }
 
 
@@ -127,11 +438,20 @@ pub struct MessageData {
     pub recipient: String,
     pub sent_at: String, 
     pub content: String,
+    /// Device of the recipient this copy is encrypted for, empty for single device recipients
+    #[serde(default)]
+    pub recipient_device: String,
+    /// MAC of the message for deniable conversations, the content is then left out of the signature
+    #[serde(default)]
+    pub mac: String,
 }
 
+/// `capabilities` lists the features supported by the client, see [`protocol`]
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct LoginData {
-    pub headers: PacketHeader
+    pub headers: PacketHeader,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
 }
 
 /// Registeration phase is for the first time you log in into a relay ever. 
@@ -141,12 +461,24 @@ pub struct LoginData {
 pub struct RegisterData {
     pub headers: PacketHeader,
     pub author_published: String,
+    /// Published post-quantum KEM encapsulation key, empty for classic-only clients
+    #[serde(default)]
+    pub author_kem_published: String,
 }
 
+/// `author_x` is the x25519 public key generated by the author for this friend. When both users
+/// support the hybrid key agreement, `kem_ciphertext` holds the KEM encapsulation made to the
+/// recipient published KEM key.
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct FriendRequestData {
     pub headers: PacketHeader,
     pub recipient: String,
+    #[serde(default)]
+    pub author_x: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub kem_ciphertext: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -154,11 +486,20 @@ pub struct RetrievePublishedData {
     pub headers: PacketHeader,
     pub recipient: String,
     pub key: String,
+    /// Devices of the recipient, served by the relay along with its published key
+    #[serde(default)]
+    pub devices: Vec<DeviceCertificate>,
+    #[serde(default)]
+    pub kem_key: String,
 }
 
+/// Error sent by the relay, `message` is meant for humans while `code` tells clients what to do,
+/// see [`ErrorCode::retry_hint`]. Relays written before the codes send none, read as `Unknown`.
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct ErrorData {
     pub headers: PacketHeader,
+    #[serde(default)]
+    pub code: ErrorCode,
     pub message: String,
 }
 
@@ -168,39 +509,365 @@ pub struct AnnouncementData {
     pub message: String,
 }
 
+/// Notice sent to each friend when the user rotates their published x25519 key.
+/// The old key stays valid until `grace_until` (unix timestamp in seconds) so that
+/// pending friend requests made with it can still be answered.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct PublishedRotationData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub old_published: String,
+    pub new_published: String,
+    pub grace_until: u64,
+}
+
+/// Distribute a revocation certificate. The header can be signed by the revoked user or by the
+/// relay forwarding it, the certificate itself carries the proof of revocation.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct RevokeData {
+    pub headers: PacketHeader,
+    pub certificate: RevocationCertificate,
+}
+
+/// List of the devices linked to an account, signed by the identity key and sent to the relay
+/// which serves it along with the published key
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DeviceListData {
+    pub headers: PacketHeader,
+    pub devices: Vec<DeviceCertificate>,
+}
+
+/// Sent by a new device to the identity it wants to be linked to, signed by the device key
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DeviceLinkRequestData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub device_id: String,
+    pub device_published: String,
+}
+
+/// Answer of an already linked device, signed by the identity key. The certificate is empty when
+/// the link has been refused.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DeviceLinkResponseData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub accepted: bool,
+    pub certificate: DeviceCertificate,
+}
+
+/// Group packets are sent once for each member, `recipient` being the member receiving this copy.
+/// Creation, invitations and kicks must be signed by an admin of the group.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct GroupCreateData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub group_id: String,
+    pub name: String,
+    pub members: Vec<String>,
+    pub admins: Vec<String>,
+}
+
+/// The invitation carries the whole group state so that the invited member can create it
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct GroupInviteData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub group_id: String,
+    pub name: String,
+    pub member: String,
+    pub members: Vec<String>,
+    pub admins: Vec<String>,
+    pub epoch: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct GroupLeaveData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub group_id: String,
+    pub epoch: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct GroupKickData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub group_id: String,
+    pub member: String,
+    pub epoch: u64,
+}
+
+/// Sender key of the author for a group epoch, encrypted with the shared key of the recipient
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SenderKeyData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub group_id: String,
+    pub epoch: u64,
+    pub key: String,
+}
+
+/// Message encrypted with the sender key of the author for the given epoch
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct GroupMessageData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub group_id: String,
+    pub epoch: u64,
+    pub sent_at: String,
+    pub content: String,
+}
+
+/// Part of an encrypted attachment, `data` being the url safe base64 of the bytes starting at
+/// `offset` in the blob. Sent by clients to upload (`blob_upload`) and by the relay to answer a
+/// download (`blob_chunk`).
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct BlobChunkData {
+    pub headers: PacketHeader,
+    pub blob_id: String,
+    pub offset: u64,
+    pub last: bool,
+    pub data: String,
+}
+
+/// Ask the relay to send (`blob_download`) or delete (`blob_delete`) an encrypted attachment.
+/// Downloads start at `offset`, so that an interrupted download can be resumed.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct BlobRequestData {
+    pub headers: PacketHeader,
+    pub blob_id: String,
+    pub offset: u64,
+}
+
+/// Share of the author identity backup, `share` is encrypted with the shared key of the recipient
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct RecoveryShareData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub backup_id: String,
+    pub threshold: u8,
+    pub share: String,
+    pub backup: String,
+}
+
+/// Sent by a user who lost their identity key to the friends holding its shares. The packet is
+/// signed by a temporary key, `recovery_x` is the x25519 key the shares must be encrypted for.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct RecoveryRequestData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub owner: String,
+    pub backup_id: String,
+    pub recovery_x: String,
+}
+
+/// Share sent back once the holder approved the recovery, encrypted with the shared key of
+/// `holder_x` and the `recovery_x` of the request
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct RecoveryResponseData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub owner: String,
+    pub backup_id: String,
+    pub threshold: u8,
+    pub holder_x: String,
+    pub share: String,
+    pub backup: String,
+}
+
+/// Message whose author is only known by the recipient: `sealed` holds a complete signed
+/// `MessageData` encrypted for the recipient published key. The headers carry no author key nor
+/// signature, the relay accepts the packet if `delivery_token` matches the token registered by the
+/// recipient.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SealedMessageData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub delivery_token: String,
+    pub sealed: String,
+}
+
+/// Register the hash of the delivery token sealed messages to the author must present. Sending a
+/// new one revokes the previous token.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DeliveryTokenRegisterData {
+    pub headers: PacketHeader,
+    pub token_hash: String,
+}
+
+/// Give the author delivery token to a friend, encrypted with their shared key
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DeliveryTokenData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub token: String,
+}
+
+/// Drop a sealed payload in a mailbox. Mailboxes are derived from the shared key of two friends and
+/// rotate regularly, so the relay can't tell who they belong to. The headers carry no author.
+/// Also used by the relay to hand the content of polled mailboxes over.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct MailboxDeliverData {
+    pub headers: PacketHeader,
+    pub mailbox: String,
+    pub payload: String,
+}
+
+/// Ask the relay for the content of the given mailboxes, without any author
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct MailboxPollData {
+    pub headers: PacketHeader,
+    pub mailboxes: Vec<String>,
+}
+
+/// Give a friend the certificate of the login key used on a relay, encrypted with their shared key
+/// so that the relays never see which identity a pseudonym belongs to
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct PseudonymData {
+    pub headers: PacketHeader,
+    pub recipient: String,
+    pub certificate: String,
+}
+
+/// Reason given by a relay when it refuses a packet, its name in snake case is stable and part of
+/// the protocol. Codes added by newer relays are read as `Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Packet that can't be read, or with missing data
+    InvalidPacket,
+    InvalidSignature,
+    /// Packet sent before a successful login
+    Unauthenticated,
+    UnknownRecipient,
+    /// Too many packets sent, the relay drops the next ones for a while
+    RateLimited,
+    /// Packet larger than the relay [`limits`]
+    PayloadTooLarge,
+    /// Protocol version or mandatory capability the relay doesn't support
+    UnsupportedVersion,
+    /// Failure of the relay itself
+    Internal,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCode::InvalidPacket => write!(f, "invalid_packet"),
+            ErrorCode::InvalidSignature => write!(f, "invalid_signature"),
+            ErrorCode::Unauthenticated => write!(f, "unauthenticated"),
+            ErrorCode::UnknownRecipient => write!(f, "unknown_recipient"),
+            ErrorCode::RateLimited => write!(f, "rate_limited"),
+            ErrorCode::PayloadTooLarge => write!(f, "payload_too_large"),
+            ErrorCode::UnsupportedVersion => write!(f, "unsupported_version"),
+            ErrorCode::Internal => write!(f, "internal"),
+            ErrorCode::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// What a client should do with a packet refused with a given [`ErrorCode`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryHint {
+    /// Send the packet again after waiting, with an increasing delay
+    Backoff,
+    /// Log in again, then send the packet again
+    Relogin,
+    /// Sending the same packet again won't work, the error should be reported
+    Never,
+}
+
+impl ErrorCode {
+    pub fn retry_hint(&self) -> RetryHint {
+        match self {
+            ErrorCode::RateLimited | ErrorCode::Internal => RetryHint::Backoff,
+            ErrorCode::Unauthenticated => RetryHint::Relogin,
+            ErrorCode::InvalidPacket
+            | ErrorCode::InvalidSignature
+            | ErrorCode::UnknownRecipient
+            | ErrorCode::PayloadTooLarge
+            | ErrorCode::UnsupportedVersion
+            | ErrorCode::Unknown => RetryHint::Never,
+        }
+    }
+}
+
+/// Code a relay answers with when it can't read a packet
+impl From<&PacketReadingError> for ErrorCode {
+    fn from(value: &PacketReadingError) -> Self {
+        match value {
+            PacketReadingError::Signature { .. } | PacketReadingError::Revoked { .. } => ErrorCode::InvalidSignature,
+            PacketReadingError::LimitExceeded { .. } => ErrorCode::PayloadTooLarge,
+            PacketReadingError::Unsupported { .. } => ErrorCode::UnsupportedVersion,
+            PacketReadingError::Key { .. } | PacketReadingError::Type { .. } | PacketReadingError::Data { .. } => ErrorCode::InvalidPacket,
+        }
+    }
+}
+
+/// Sent by the relay once it accepted a packet, `headers.request_id` is the one of that packet
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct AckData {
+    pub headers: PacketHeader,
+}
+
+/// Sent by the relay when it refuses a packet, `headers.request_id` is the one of that packet
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct NackData {
+    pub headers: PacketHeader,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
 
 pub trait RelayPacketGeneration {
-    fn new(content: &str) -> Self;
+    /// Data given along with the content: an [`ErrorContext`] for an error, an
+    /// [`AnnouncementContext`] for an announcement
+    type Context<'a>;
+
+    fn new(context: Self::Context<'_>, content: &str) -> Self;
 }
 
-/// Signature payload of the RelayMessage is action + relay_key + content
+/// Error answering `request`, whose request id is echoed, or a packet that couldn't be read at all
+/// when `request` is `None`
+pub struct ErrorContext<'a> {
+    pub relay_key: &'a str,
+    pub request: Option<&'a Packet>,
+    pub code: ErrorCode,
+}
+
+/// Signature payload of the ErrorData is action + relay_key + code + ":" + content
 impl RelayPacketGeneration for ErrorData {
-    fn new(message: &str) -> Self {
-        let relay_config = crate::config::get_config();
-        let relay_key = fs::read_to_string(relay_config.me.public_ed_path).expect("Couldn't read relay public key");
+    type Context<'a> = ErrorContext<'a>;
 
+    fn new(context: ErrorContext<'_>, message: &str) -> Self {
         Self {
-            headers: PacketHeader {
-                action: String::from("error"),
-                author_key: relay_key,
-                signature: String::default()
-            },
+            headers: requests::response_headers("error", context.relay_key, context.request),
+            code: context.code,
             message: message.to_string(),
         }
     }
 }
 
+/// Announcement written by the relay owning `relay_key`
+pub struct AnnouncementContext<'a> {
+    pub relay_key: &'a str,
+}
 
+/// Signature payload of the AnnouncementData is action + relay_key + content
 impl RelayPacketGeneration for AnnouncementData {
-    fn new(message: &str) -> Self {
-        let relay_config = crate::config::get_config();
-        let relay_key = fs::read_to_string(relay_config.me.public_ed_path).expect("Couldn't read relay public key");
+    type Context<'a> = AnnouncementContext<'a>;
 
+    fn new(context: AnnouncementContext<'_>, message: &str) -> Self {
         Self {
             headers: PacketHeader {
                 action: String::from("announcement"),
-                author_key: relay_key,
-                signature: String::default()
+                author_key: context.relay_key.to_string(),
+                signature: String::default(),
+                ..Default::default()
             },
             message: message.to_string(),
         }
@@ -212,48 +879,343 @@ impl RelayPacketGeneration for AnnouncementData {
 /// packet_data is a collections or all the data required for this packet in order and split by "__".  
 /// **Example**:
 /// ```rust
-/// use plume::packet;
+/// use plume_core::packets::{extract_and_verify, Packet, PacketReadingError};
 ///
 /// let login_packet = r#"
 ///     {
-///         "type": "login",
-///         "author_key": "<MyKey>"
+///         "headers": {
+///             "action": "login",
+///             "author_key": "<MyKey>",
+///             "signature": "<PacketSignature>"
+///         }
 ///     }"#;
-/// let received = String::from(login_packet);
-/// let packet: Packet = extract_packet(received);
+/// let packet: Result<Packet, PacketReadingError> = extract_and_verify(login_packet, &[]);
 /// ```
 ///
-pub fn extract_and_verify (data: &str) -> Result<Packet, PacketReadingError> {
+/// `revoked_keys` are the revoked keys of the configuration, packets written by one of them are
+/// refused.
+pub fn extract_and_verify (data: &str, revoked_keys: &[String]) -> Result<Packet, PacketReadingError> {
     let packet = extract(data)?;
 
-    verify_packet_signature(&packet)?;
+    verify_packet_signature(&packet, revoked_keys)?;
 
     Ok(packet)
 }
 
 pub fn extract(data: &str) -> Result<Packet, PacketReadingError> {
-    let packet: Value = serde_json::from_str(data)?;
-    let packet_type = packet["headers"]["action"].as_str().unwrap_or_default();
-    println!("Packet Type is : {}", packet_type);
+    Packet::from_json(data)
+}
 
-    match packet_type {
-        "login" => {
-            Ok(Packet::Login(serde_json::from_str(data)?))
-        }
-        "message" => {
-            Ok(Packet::Message(serde_json::from_str(data)?))
+impl Packet {
+    /// Encode the packet in the json format read by [`Packet::from_json`]
+    pub fn to_json(&self) -> Result<String, PacketGenerationError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Decode a packet of any type with the default limits, without verifying its signature
+    pub fn from_json(data: &str) -> Result<Packet, PacketReadingError> {
+        Packet::from_json_with_limits(data, &PacketLimits::DEFAULT)
+    }
+
+    pub fn from_json_with_limits(data: &str, limits: &PacketLimits) -> Result<Packet, PacketReadingError> {
+        check_packet_size(data.len(), limits)?;
+        Packet::from_value_with_limits(serde_json::from_str(data)?, limits)
+    }
+
+    /// Build the packet designated by `headers.action` from its parsed json. The action being
+    /// nested in the headers, the json is parsed once then each variant is read from the parsed
+    /// value, the same way serde handles internally tagged enums.
+    pub fn from_value(packet: Value) -> Result<Packet, PacketReadingError> {
+        Packet::from_value_with_limits(packet, &PacketLimits::DEFAULT)
+    }
+
+    pub fn from_value_with_limits(packet: Value, limits: &PacketLimits) -> Result<Packet, PacketReadingError> {
+        let action = packet["headers"]["action"].as_str().unwrap_or_default().to_string();
+        // an oversized action isn't copied in the error
+        check_packet_fields(&packet, limits).map_err(|e| if e.field() == Some("action") { e } else { e.with_action(&action) })?;
+        check_version(packet["headers"]["version"].as_u64().unwrap_or_default()).map_err(|e| e.with_action(&action))?;
+        requests::check_request_id(packet["headers"]["request_id"].as_str().unwrap_or_default()).map_err(|e| e.with_action(&action))?;
+
+        let mut packet = Packet::parse_action(packet, action)?;
+        // the request id and the code of errors written before the protocol version aren't signed,
+        // they can't be trusted
+        if packet.headers().version == 0 {
+            packet.headers_mut().request_id.clear();
         }
-        "friend_request" => {
-            Ok(Packet::FriendRequest(serde_json::from_str(data)?))
+        if let Packet::Error(error) = &mut packet
+            && error.headers.version == 0 {
+            error.code = ErrorCode::Unknown;
         }
-        "retrieve_published" => {
-            todo!("Generate the handle of this action")
+        Ok(packet)
+    }
+}
+
+/// Deserialize the data of a packet, errors telling the path of the field at fault
+fn parse_packet<T: DeserializeOwned>(packet: Value, action: &str) -> Result<T, PacketReadingError> {
+    serde_path_to_error::deserialize(packet).map_err(|e| PacketReadingError::from(e).with_action(action))
+}
+
+/// Packets are written without any wrapper, the variant being given by `headers.action`
+impl<'de> Deserialize<'de> for Packet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Packet::from_value(Value::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+
+    use crate::encryption::{keys::generate_ed_keys, signature::sign_packet};
+
+    use super::*;
+
+    /// Action expected for each variant, without wildcard so that a new variant can't be added
+    /// without being covered here
+    fn expected_action(packet: &Packet) -> &'static str {
+        match packet {
+            Packet::Login(_) => "login",
+            Packet::Message(_) => "message",
+            Packet::FriendRequest(_) => "friend_request",
+            Packet::RetrievePublished(_) => "retrieve_published",
+            Packet::Register(_) => "register",
+            Packet::Announcement(_) => "announcement",
+            Packet::Error(_) => "error",
+            Packet::PublishedRotation(_) => "published_rotation",
+            Packet::Revoke(_) => "revoke",
+            Packet::DeviceList(_) => "device_list",
+            Packet::DeviceLinkRequest(_) => "device_link_request",
+            Packet::DeviceLinkResponse(_) => "device_link_response",
+            Packet::GroupCreate(_) => "group_create",
+            Packet::GroupInvite(_) => "group_invite",
+            Packet::GroupLeave(_) => "group_leave",
+            Packet::GroupKick(_) => "group_kick",
+            Packet::SenderKey(_) => "sender_key",
+            Packet::GroupMessage(_) => "group_message",
+            Packet::BlobUpload(_) => "blob_upload",
+            Packet::BlobChunk(_) => "blob_chunk",
+            Packet::BlobDownload(_) => "blob_download",
+            Packet::BlobDelete(_) => "blob_delete",
+            Packet::RecoveryShare(_) => "recovery_share",
+            Packet::RecoveryRequest(_) => "recovery_request",
+            Packet::RecoveryResponse(_) => "recovery_response",
+            Packet::SealedMessage(_) => "sealed_message",
+            Packet::DeliveryTokenRegister(_) => "delivery_token_register",
+            Packet::DeliveryToken(_) => "delivery_token",
+            Packet::MailboxDeliver(_) => "mailbox_deliver",
+            Packet::MailboxPoll(_) => "mailbox_poll",
+            Packet::Pseudonym(_) => "pseudonym",
+            Packet::Ack(_) => "ack",
+            Packet::Nack(_) => "nack",
         }
-        "register" => {
-            Ok(Packet::Register(serde_json::from_str(data)?))
+    }
+
+    macro_rules! sample {
+        ($variant:ident, $data:ident) => {{
+            let mut data = $data::default();
+            data.headers = PacketHeader { action: String::from("placeholder"), author_key: String::from("author"), signature: String::from("signature"), ..Default::default() };
+            let mut packet = Packet::$variant(data);
+            let action = expected_action(&packet);
+            if let Packet::$variant(data) = &mut packet {
+                data.headers.action = action.to_string();
+            }
+            packet
+        }};
+    }
+
+    #[test]
+    fn test_every_packet_round_trip() {
+        let samples = [
+            sample!(Login, LoginData),
+            sample!(Message, MessageData),
+            sample!(FriendRequest, FriendRequestData),
+            sample!(RetrievePublished, RetrievePublishedData),
+            sample!(Register, RegisterData),
+            sample!(Announcement, AnnouncementData),
+            sample!(Error, ErrorData),
+            sample!(PublishedRotation, PublishedRotationData),
+            sample!(Revoke, RevokeData),
+            sample!(DeviceList, DeviceListData),
+            sample!(DeviceLinkRequest, DeviceLinkRequestData),
+            sample!(DeviceLinkResponse, DeviceLinkResponseData),
+            sample!(GroupCreate, GroupCreateData),
+            sample!(GroupInvite, GroupInviteData),
+            sample!(GroupLeave, GroupLeaveData),
+            sample!(GroupKick, GroupKickData),
+            sample!(SenderKey, SenderKeyData),
+            sample!(GroupMessage, GroupMessageData),
+            sample!(BlobUpload, BlobChunkData),
+            sample!(BlobChunk, BlobChunkData),
+            sample!(BlobDownload, BlobRequestData),
+            sample!(BlobDelete, BlobRequestData),
+            sample!(RecoveryShare, RecoveryShareData),
+            sample!(RecoveryRequest, RecoveryRequestData),
+            sample!(RecoveryResponse, RecoveryResponseData),
+            sample!(SealedMessage, SealedMessageData),
+            sample!(DeliveryTokenRegister, DeliveryTokenRegisterData),
+            sample!(DeliveryToken, DeliveryTokenData),
+            sample!(MailboxDeliver, MailboxDeliverData),
+            sample!(MailboxPoll, MailboxPollData),
+            sample!(Pseudonym, PseudonymData),
+            sample!(Ack, AckData),
+            sample!(Nack, NackData),
+        ];
+
+        for packet in &samples {
+            let encoded = packet.to_json().expect("Unable to encode packet");
+            let Ok(decoded) = Packet::from_json(&encoded) else {
+                panic!("Unable to decode {} packet", expected_action(packet));
+            };
+            assert_eq!(expected_action(&decoded), expected_action(packet));
+            assert_eq!(decoded.to_json().expect("Unable to encode packet"), encoded);
         }
-        _ => {
-            Err(PacketReadingError::Type)
+
+        // every action is decoded to its own variant, and every variant has a sample
+        let mut actions: Vec<&str> = samples.iter().map(expected_action).collect();
+        actions.sort();
+        actions.dedup();
+        assert_eq!(actions.len(), samples.len());
+        let mut declared = ACTIONS.to_vec();
+        declared.sort();
+        assert_eq!(actions, declared, "Every packet type must have a sample");
+    }
+
+    #[test]
+    fn test_relay_packets_round_trip() {
+        let packet = Packet::Error(ErrorData {
+            headers: PacketHeader { action: String::from("error"), author_key: String::from("relay"), ..Default::default() },
+            code: ErrorCode::UnknownRecipient,
+            message: String::from("unknown recipient"),
+        });
+
+        let Ok(Packet::Error(decoded)) = extract(&packet.to_json().expect("Unable to encode packet")) else {
+            panic!("Unable to decode error packet");
+        };
+        assert_eq!(decoded.message, "unknown recipient");
+        assert_eq!(decoded.code, ErrorCode::UnknownRecipient);
+        assert_eq!(decoded.code.retry_hint(), RetryHint::Never);
+
+        // errors of relays written before the codes
+        let Ok(Packet::Error(legacy)) = extract(r#"{"headers":{"action":"error","author_key":"relay","signature":""},"message":"slow down"}"#) else {
+            panic!("Unable to decode error packet without code");
+        };
+        assert_eq!(legacy.code, ErrorCode::Unknown);
+    }
+
+    #[test]
+    fn test_extract_and_verify_revoked() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let mut packet = Packet::Login(LoginData {
+            headers: PacketHeader { action: String::from("login"), author_key: public_ed.clone(), ..Default::default() },
+            capabilities: Vec::new(),
+        });
+        sign_packet(&mut packet, &private_ed).expect("Unable to sign packet");
+        let data = packet.to_json().expect("Unable to encode packet");
+
+        assert!(extract_and_verify(&data, &[]).is_ok());
+        assert!(matches!(extract_and_verify(&data, &[public_ed]), Err(PacketReadingError::Revoked { .. })));
+    }
+
+    #[test]
+    fn test_error_code_signed() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let mut packet = Packet::Error(ErrorData {
+            headers: PacketHeader { action: String::from("error"), author_key: public_ed, ..Default::default() },
+            code: ErrorCode::RateLimited,
+            message: String::from("slow down"),
+        });
+        sign_packet(&mut packet, &private_ed).expect("Unable to sign packet");
+        assert!(verify_packet_signature(&packet, &[]).is_ok());
+
+        if let Packet::Error(data) = &mut packet {
+            data.code = ErrorCode::Internal;
         }
+        assert!(verify_packet_signature(&packet, &[]).is_err());
+
+        let error = PacketReadingError::LimitExceeded { action: String::new(), field: String::from("content"), length: 2, max: 1 };
+        assert_eq!(ErrorCode::from(&error), ErrorCode::PayloadTooLarge);
+        let error = error.with_action("message");
+        assert_eq!(error.action(), Some("message"));
+        assert_eq!(error.to_string(), "Packet content is too large (2 > 1) (action message)");
+        assert_eq!(ErrorCode::Unauthenticated.retry_hint(), RetryHint::Relogin);
+    }
+
+    #[test]
+    fn test_announcement_generation() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let mut packet = Packet::Announcement(AnnouncementData::new(AnnouncementContext { relay_key: &public_ed }, "maintenance"));
+        sign_packet(&mut packet, &private_ed).expect("Unable to sign packet");
+        assert!(matches!(&packet, Packet::Announcement(data) if data.headers.author_key == public_ed && data.message == "maintenance"));
+        assert!(verify_packet_signature(&packet, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_unsigned_error_code_ignored() {
+        let data = r#"{"headers":{"action":"error","author_key":"relay","signature":"signature","version":0},"code":"rate_limited","message":"slow down"}"#;
+        let Ok(Packet::Error(error)) = Packet::from_json(data) else {
+            panic!("Unable to read error");
+        };
+        assert_eq!(error.code, ErrorCode::Unknown);
+        assert_eq!(error.message, "slow down");
+
+        let data = r#"{"headers":{"action":"error","author_key":"relay","signature":"signature","version":1},"code":"rate_limited","message":"slow down"}"#;
+        assert!(matches!(Packet::from_json(data), Ok(Packet::Error(error)) if error.code == ErrorCode::RateLimited));
+    }
+
+    #[test]
+    fn test_unsigned_request_id_ignored() {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let data = format!(r#"{{"headers":{{"action":"ack","author_key":"relay","signature":"signature","version":0,"request_id":"{request_id}"}}}}"#);
+        assert!(matches!(Packet::from_json(&data), Ok(packet) if packet.headers().request_id.is_empty()));
+
+        let data = data.replace(r#""version":0"#, r#""version":1"#);
+        assert!(matches!(Packet::from_json(&data), Ok(packet) if packet.headers().request_id == request_id));
+    }
+
+    #[test]
+    fn test_packet_single_pass_round_trip() {
+        let data = r#"{"headers":{"action":"message","author_key":"author","signature":"signature","version":1},"recipient":"recipient","sent_at":"2025-01-01T00:00:00Z","content":"hello","recipient_device":"","mac":""}"#;
+
+        let Ok(packet) = serde_json::from_str::<Packet>(data) else {
+            panic!("Unable to read packet");
+        };
+        assert!(matches!(&packet, Packet::Message(message) if message.content == "hello"));
+
+        let encoded = serde_json::to_value(&packet).expect("Unable to write packet");
+        assert_eq!(encoded, serde_json::from_str::<Value>(data).expect("Invalid json"));
+        assert!(serde_json::from_str::<Packet>(r#"{"headers":{"action":"unknown"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_reading_error_context() {
+        let data = r#"{"headers":{"action":"message","author_key":"author","signature":"signature"},"recipient":"recipient","sent_at":"2025-01-01T00:00:00Z","content":12}"#;
+
+        let Err(error) = Packet::from_json(data) else {
+            panic!("Packet with an invalid content has been read");
+        };
+        assert_eq!(error.action(), Some("message"));
+        assert_eq!(error.field(), Some("content"));
+        assert!(std::error::Error::source(&error).is_some());
+        assert!(error.to_string().contains("field content"));
+
+        let Err(error) = Packet::from_json(r#"{"headers":{"action":"unknown"}}"#) else {
+            panic!("Packet with an unknown action has been read");
+        };
+        assert!(matches!(error, PacketReadingError::Type { action } if action == "unknown"));
+    }
+    #[test]
+    fn test_generation_error_context() {
+        let mut packet = Packet::Message(MessageData {
+            headers: PacketHeader { action: String::from("message"), ..Default::default() },
+            ..Default::default()
+        });
+
+        let Err(error) = sign_packet(&mut packet, "not a key") else {
+            panic!("Packet has been signed with an invalid key");
+        };
+        assert_eq!(error.action(), Some("message"));
+        assert_eq!(error.field(), Some("private_key"));
+        assert!(std::error::Error::source(&error).is_some());
+        assert_eq!(error.to_string(), "Invalid signing (private ED_25519) key provided (action message, field private_key)");
     }
 }
@@ -0,0 +1,99 @@
+use crate::{config::{Friend, Me}, encryption::hybrid, packets::PacketReadingError};
+
+/// Version of the protocol written by this client
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest version still read, 0 being the packets written before the version field existed
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+
+/// Capabilities starting with this prefix are mandatory: a peer that doesn't support them must
+/// refuse the packet instead of ignoring them
+pub const MANDATORY_PREFIX: char = '!';
+
+pub const CIPHER_CHACHA20POLY1305: &str = "cipher_chacha20poly1305";
+pub const RATCHET: &str = "ratchet";
+pub const ATTACHMENTS: &str = "attachments";
+pub const GROUPS: &str = "groups";
+pub const PADDING: &str = "padding";
+/// Binary CBOR wire format, see [`crate::packets::encoding`]
+pub const ENCODING_CBOR: &str = "encoding_cbor";
+/// ed25519 keys read in their compact form, see [`crate::encryption::keys::encode_ed_public`]
+pub const COMPACT_KEYS: &str = "compact_keys";
+
+/// Capabilities to advertise at login and in friend requests
+pub fn local_capabilities(me: &Me) -> Vec<String> {
+    let mut capabilities: Vec<String> = [CIPHER_CHACHA20POLY1305, ATTACHMENTS, GROUPS, PADDING, COMPACT_KEYS].map(String::from).to_vec();
+    capabilities.extend(hybrid::local_capabilities(me));
+    if cfg!(feature = "cbor") {
+        capabilities.push(ENCODING_CBOR.to_string());
+    }
+    capabilities
+}
+
+/// Packets written in a newer version than ours can't be read safely
+pub fn check_version(version: u64) -> Result<(), PacketReadingError> {
+    if version < MIN_PROTOCOL_VERSION as u64 || version > PROTOCOL_VERSION as u64 {
+        return Err(PacketReadingError::Unsupported { action: String::new(), feature: format!("protocol version {version}") });
+    }
+    Ok(())
+}
+
+/// Refuse the capabilities lists containing a mandatory capability that is not in `local`, the
+/// capabilities we support (see [`local_capabilities`]), even if this client knows about it.
+/// Unsupported optional capabilities are ignored.
+pub fn check_capabilities(capabilities: &[String], local: &[String]) -> Result<(), PacketReadingError> {
+    match capabilities.iter()
+        .filter_map(|capability| capability.strip_prefix(MANDATORY_PREFIX))
+        .find(|capability| !local.iter().any(|supported| supported == capability)) {
+        Some(capability) => Err(PacketReadingError::Unsupported { action: String::new(), feature: format!("capability {capability}") }),
+        None => Ok(()),
+    }
+}
+
+/// Capabilities supported by both sides, without their mandatory marker
+pub fn negotiate_capabilities(local: &[String], remote: &[String]) -> Vec<String> {
+    let strip = |capability: &String| capability.trim_start_matches(MANDATORY_PREFIX).to_string();
+    let remote: Vec<String> = remote.iter().map(strip).collect();
+
+    local.iter().map(strip).filter(|capability| remote.contains(capability)).collect()
+}
+
+/// Store the version and capabilities a friend advertised during the handshake, they decide what
+/// can be sent to this friend. The handshake is refused if the friend requires a capability we
+/// don't support.
+pub fn store_friend_capabilities(friend: &mut Friend, me: &Me, version: u32, capabilities: &[String]) -> Result<(), PacketReadingError> {
+    let local = local_capabilities(me);
+    check_capabilities(capabilities, &local)?;
+
+    friend.protocol_version = version.min(PROTOCOL_VERSION);
+    friend.capabilities = negotiate_capabilities(&local, capabilities);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{Friend, Me};
+
+    use super::{check_capabilities, check_version, local_capabilities, negotiate_capabilities, store_friend_capabilities, GROUPS, PROTOCOL_VERSION};
+
+    #[test]
+    fn test_capabilities() {
+        assert!(check_version(0).is_ok());
+        assert!(check_version(PROTOCOL_VERSION as u64 + 1).is_err());
+
+        let local = local_capabilities(&Me::default());
+        assert!(check_capabilities(&[String::from("!groups"), String::from("unknown_feature")], &local).is_ok());
+        assert!(check_capabilities(&[String::from("!unknown_feature")], &local).is_err());
+        // known by this client but not supported
+        assert!(check_capabilities(&[String::from("!ratchet")], &local).is_err());
+        assert!(check_capabilities(&[String::from("!hybrid_x25519_mlkem768")], &local).is_err());
+
+        let mut friend = Friend::default();
+        assert!(store_friend_capabilities(&mut friend, &Me::default(), PROTOCOL_VERSION, &[String::from("!ratchet")]).is_err());
+        assert!(store_friend_capabilities(&mut friend, &Me::default(), PROTOCOL_VERSION, &[String::from("!groups")]).is_ok());
+        assert_eq!(friend.capabilities, vec![GROUPS.to_string()]);
+
+        let common = negotiate_capabilities(&[String::from("groups"), String::from("attachments")], &[String::from("!groups")]);
+        assert_eq!(common, vec![GROUPS.to_string()]);
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::packets::{AckData, ErrorCode, NackData, Packet, PacketHeader, PacketReadingError};
+
+/// Identifier of a new request, see [`PacketHeader::request_id`]
+pub fn new_request_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Request ids are either left empty or a UUID, as written by [`new_request_id`]
+pub(crate) fn check_request_id(request_id: &str) -> Result<(), PacketReadingError> {
+    if request_id.is_empty() {
+        return Ok(());
+    }
+
+    Uuid::try_parse(request_id).map_err(|e| PacketReadingError::data("headers.request_id").with_source(e))?;
+    Ok(())
+}
+
+/// Headers of a relay response written with `relay_key`, echoing the request id of `request` when
+/// the response answers a packet that could be read
+pub(crate) fn response_headers(action: &str, relay_key: &str, request: Option<&Packet>) -> PacketHeader {
+    PacketHeader {
+        action: action.to_string(),
+        author_key: relay_key.to_string(),
+        signature: String::default(),
+        request_id: request.map(|request| request.headers().request_id.clone()).unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+/// Signature payload of the Ack is action + relay_key, the echoed request id being covered by the
+/// headers
+impl AckData {
+    pub fn acknowledge(relay_key: &str, request: &Packet) -> Self {
+        Self { headers: response_headers("ack", relay_key, Some(request)) }
+    }
+}
+
+/// Signature payload of the Nack is action + relay_key + code + ":" + message
+impl NackData {
+    pub fn reject(relay_key: &str, request: &Packet, code: ErrorCode, message: &str) -> Self {
+        Self {
+            headers: response_headers("nack", relay_key, Some(request)),
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Answer of the relay to one of our requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Accepted { request_id: String, action: String },
+    Rejected { request_id: String, action: String, code: ErrorCode, message: String },
+}
+
+/// Requests sent to the relay and still waiting for their answer, indexed by request id. `action`
+/// is the action of the request.
+#[derive(Debug, Default)]
+pub struct PendingRequests {
+    requests: HashMap<String, String>,
+}
+
+impl PendingRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give the packet a request id, unless it already has one, and wait for its answer.
+    /// Must be called before signing the packet, the request id being part of the signature.
+    pub fn track(&mut self, packet: &mut Packet) -> String {
+        let headers = packet.headers_mut();
+        if headers.request_id.is_empty() {
+            headers.request_id = new_request_id();
+        }
+
+        self.requests.insert(headers.request_id.clone(), headers.action.clone());
+        headers.request_id.clone()
+    }
+
+    /// Match a packet received from the relay with the request it answers. Returns `None` for
+    /// packets that aren't answers, or answers to requests we aren't waiting for.
+    ///
+    /// The signature of the response must have been verified, and its author checked to be the relay.
+    pub fn resolve(&mut self, response: &Packet) -> Option<Response> {
+        let request_id = &response.headers().request_id;
+        if request_id.is_empty() || !matches!(response, Packet::Ack(_) | Packet::Nack(_) | Packet::Error(_)) {
+            return None;
+        }
+
+        let action = self.requests.remove(request_id)?;
+        let request_id = request_id.clone();
+        Some(match response {
+            Packet::Nack(data) => Response::Rejected { request_id, action, code: data.code, message: data.message.clone() },
+            Packet::Error(data) => Response::Rejected { request_id, action, code: data.code, message: data.message.clone() },
+            _ => Response::Accepted { request_id, action },
+        })
+    }
+
+    pub fn is_pending(&self, request_id: &str) -> bool {
+        self.requests.contains_key(request_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{encryption::{keys::generate_ed_keys, signature::{sign_packet, verify_packet_signature}}, packets::{AckData, ErrorCode, ErrorContext, ErrorData, MessageData, NackData, Packet, PacketHeader, RelayPacketGeneration}};
+
+    use super::{PendingRequests, Response};
+
+    fn response(packet: Packet, private_ed: &str) -> Packet {
+        let mut packet = packet;
+        sign_packet(&mut packet, private_ed).expect("Unable to sign response");
+        let Ok(decoded) = Packet::from_json(&packet.to_json().expect("Unable to encode response")) else {
+            panic!("Unable to decode response");
+        };
+        decoded
+    }
+
+    #[test]
+    fn test_pending_requests() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let mut pending = PendingRequests::new();
+
+        let mut message = Packet::Message(MessageData {
+            headers: PacketHeader { action: String::from("message"), ..Default::default() },
+            ..Default::default()
+        });
+        let message_id = pending.track(&mut message);
+        let mut login = Packet::Message(MessageData {
+            headers: PacketHeader { action: String::from("login"), ..Default::default() },
+            ..Default::default()
+        });
+        let login_id = pending.track(&mut login);
+        assert_ne!(message_id, login_id);
+        assert_eq!(pending.len(), 2);
+
+        let ack = response(Packet::Ack(AckData::acknowledge(&public_ed, &message)), &private_ed);
+        assert!(verify_packet_signature(&ack, &[]).is_ok());
+        assert_eq!(pending.resolve(&ack), Some(Response::Accepted { request_id: message_id.clone(), action: String::from("message") }));
+        assert_eq!(pending.resolve(&ack), None);
+
+        let nack = response(Packet::Nack(NackData::reject(&public_ed, &login, ErrorCode::Unauthenticated, "unknown account")), &private_ed);
+        assert!(matches!(pending.resolve(&nack), Some(Response::Rejected { code: ErrorCode::Unauthenticated, .. })));
+        assert!(pending.is_empty());
+        assert_eq!(serde_json::from_str::<ErrorCode>(r#""code_of_a_newer_relay""#).ok(), Some(ErrorCode::Unknown));
+
+        // the request id is signed
+        let Packet::Ack(mut replayed) = ack else {
+            panic!("Ack decoded to another packet");
+        };
+        replayed.headers.request_id = login_id;
+        assert!(verify_packet_signature(&Packet::Ack(replayed), &[]).is_err());
+    }
+
+    #[test]
+    fn test_error_echoes_request_id() {
+        let (private_ed, public_ed) = generate_ed_keys();
+        let mut pending = PendingRequests::new();
+
+        let mut message = Packet::Message(MessageData {
+            headers: PacketHeader { action: String::from("message"), ..Default::default() },
+            ..Default::default()
+        });
+        let message_id = pending.track(&mut message);
+
+        let context = ErrorContext { relay_key: &public_ed, request: Some(&message), code: ErrorCode::RateLimited };
+        let error = response(Packet::Error(ErrorData::new(context, "slow down")), &private_ed);
+        assert_eq!(error.headers().request_id, message_id);
+        assert!(matches!(pending.resolve(&error), Some(Response::Rejected { code: ErrorCode::RateLimited, .. })));
+
+        let context = ErrorContext { relay_key: &public_ed, request: None, code: ErrorCode::InvalidPacket };
+        assert!(ErrorData::new(context, "unreadable packet").headers.request_id.is_empty());
+    }
+
+    #[test]
+    fn test_request_id_must_be_uuid() {
+        let data = r#"{"headers":{"action":"ack","author_key":"relay","signature":"signature","version":1,"request_id":"1:2"}}"#;
+        let Err(error) = Packet::from_json(data) else {
+            panic!("Packet with an invalid request id has been read");
+        };
+        assert_eq!(error.field(), Some("headers.request_id"));
+        assert_eq!(error.action(), Some("ack"));
+    }
+}
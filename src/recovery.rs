@@ -0,0 +1,388 @@
+use std::{collections::HashMap, fmt::Display, fs};
+
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use ed25519_dalek::{pkcs8::DecodePrivateKey, SigningKey};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sharks::{Share, Sharks};
+use uuid::Uuid;
+
+use crate::{config::{Config, HeldShare, RecoverySetup}, current_timestamp, encryption::{decrypt_payload, encrypt_payload, keys::{ed_key_id, encode_ed_public, generate_ed_keys, generate_shared_key, generate_x_keys, same_ed_key}, signature::{compact_author_key, sign_packet, verify_packet, verify_packet_signature}}, packets::{Packet, PacketGenerationError, PacketHeader, RecoveryRequestData, RecoveryResponseData, RecoveryShareData}, transactions::{self, StorageError, Transaction, TransactionType}};
+
+#[derive(Debug)]
+pub enum RecoveryError {
+    Io(std::io::Error),
+    Storage(StorageError),
+    Generation(PacketGenerationError),
+    WrongPacket,
+    InvalidThreshold,
+    UnknownHolder,
+    InvalidSignature,
+    NoShare,
+    InvalidShare,
+    /// Request answered with the transaction of another request
+    TransactionMismatch,
+}
+
+impl Display for RecoveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecoveryError::Io(e) => {
+                write!(f, "IO error: {}", e)
+            }
+            RecoveryError::Storage(e) => {
+                write!(f, "Transaction error: {}", e)
+            }
+            RecoveryError::Generation(e) => {
+                write!(f, "Unable to generate recovery packet: {}", e)
+            }
+            RecoveryError::WrongPacket => {
+                write!(f, "Unexpected packet type for the recovery process")
+            }
+            RecoveryError::InvalidThreshold => {
+                write!(f, "Threshold must be between 1 and the number of holders")
+            }
+            RecoveryError::UnknownHolder => {
+                write!(f, "Share holder is not a friend")
+            }
+            RecoveryError::InvalidSignature => {
+                write!(f, "Recovery packet has an invalid signature")
+            }
+            RecoveryError::NoShare => {
+                write!(f, "No share held for this backup")
+            }
+            RecoveryError::InvalidShare => {
+                write!(f, "Unable to read or combine the recovery shares")
+            }
+            RecoveryError::TransactionMismatch => {
+                write!(f, "Recovery request does not match the approval transaction")
+            }
+        }
+    }
+}
+impl std::error::Error for RecoveryError {}
+
+/// State of a recovery on the device of the user who lost their identity key.
+/// It must be kept (on disk if needed) until enough shares have been received.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecoverySession {
+    pub owner: String,
+    pub backup_id: String,
+    pub holders: Vec<String>,
+    pub threshold: u8,
+    pub transaction_id: String,
+    pub private_x: String,
+    /// Shares received, indexed by [`ed_key_id`] of their holder
+    pub shares: HashMap<String, String>,
+}
+
+/// Split a backup of the identity key between `holders` (public ed25519 keys of friends), any
+/// `threshold` of them can help restore it. The identity key is encrypted with a random key and
+/// only this key is split, every holder also receives the encrypted backup.
+pub fn create_recovery_shares(config: &mut Config, holders: &[String], threshold: u8) -> Result<Vec<Packet>, RecoveryError> {
+    if threshold == 0 || threshold as usize > holders.len() || holders.len() > u8::MAX as usize {
+        return Err(RecoveryError::InvalidThreshold);
+    }
+    let friends = holders.iter()
+        .map(|holder| config.friends.values().find(|friend| same_ed_key(&friend.public_ed, holder)).ok_or(RecoveryError::UnknownHolder))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let public_ed = fs::read_to_string(&config.me.public_ed_path)?;
+    let private_ed = fs::read_to_string(&config.me.private_ed_path)?;
+
+    let mut backup_key = [0u8; 32];
+    OsRng.fill_bytes(&mut backup_key);
+    let backup = encrypt_payload(&private_ed, &URL_SAFE.encode(backup_key))?;
+    let backup_id = Uuid::new_v4().to_string();
+
+    let shares = Sharks(threshold).dealer(&backup_key);
+    let mut packets = Vec::with_capacity(holders.len());
+    for (friend, share) in friends.iter().zip(shares) {
+        let mut headers = PacketHeader {
+            action: String::from("recovery_share"),
+            author_key: public_ed.clone(),
+            signature: String::default(),
+            ..Default::default()
+        };
+        compact_author_key(&mut headers, &friend.capabilities);
+        let mut packet = Packet::RecoveryShare(RecoveryShareData {
+            headers,
+            recipient: friend.public_ed.clone(),
+            backup_id: backup_id.clone(),
+            threshold,
+            share: encrypt_payload(&URL_SAFE.encode(Vec::from(&share)), &friend.shared_key)?,
+            backup: backup.clone(),
+        });
+        sign_packet(&mut packet, &private_ed)?;
+        packets.push(packet);
+    }
+
+    config.me.recovery = Some(RecoverySetup { backup_id, threshold, holders: holders.to_vec() });
+    Ok(packets)
+}
+
+/// Store a share received from a friend, replacing any older share of the same friend.
+/// The caller is responsible for writing the configuration.
+pub fn store_recovery_share(config: &mut Config, packet: &Packet) -> Result<(), RecoveryError> {
+    let Packet::RecoveryShare(data) = packet else {
+        return Err(RecoveryError::WrongPacket);
+    };
+    verify_packet(config, packet).map_err(|_| RecoveryError::InvalidSignature)?;
+
+    let friend = config.friends.values().find(|friend| same_ed_key(&friend.public_ed, &data.headers.author_key)).ok_or(RecoveryError::UnknownHolder)?;
+    let share = decrypt_payload(&data.share, &friend.shared_key).map_err(|_| RecoveryError::InvalidShare)?;
+
+    config.held_shares.insert(ed_key_id(&data.headers.author_key), HeldShare {
+        backup_id: data.backup_id.clone(),
+        threshold: data.threshold,
+        share,
+        backup: data.backup.clone(),
+        received_at: current_timestamp(),
+    });
+    Ok(())
+}
+
+/// Start a recovery from a new device. `setup` is the backup made with
+/// [`create_recovery_shares`], learned out of band since the configuration is lost.
+pub fn request_recovery(owner: &str, setup: &RecoverySetup) -> Result<(RecoverySession, Vec<Packet>), RecoveryError> {
+    if setup.threshold == 0 || setup.threshold as usize > setup.holders.len() {
+        return Err(RecoveryError::InvalidThreshold);
+    }
+    // the requests can't be signed by the lost identity, a temporary key is used instead
+    let (temporary_private, temporary_public) = generate_ed_keys();
+    let (private_x, public_x) = generate_x_keys();
+
+    let mut packets = Vec::with_capacity(setup.holders.len());
+    for holder in &setup.holders {
+        let mut packet = Packet::RecoveryRequest(RecoveryRequestData {
+            headers: PacketHeader {
+                action: String::from("recovery_request"),
+                author_key: temporary_public.clone(),
+                signature: String::default(),
+                ..Default::default()
+            },
+            recipient: holder.clone(),
+            owner: owner.to_string(),
+            backup_id: setup.backup_id.clone(),
+            recovery_x: public_x.clone(),
+        });
+        sign_packet(&mut packet, &temporary_private)?;
+        packets.push(packet);
+    }
+
+    let session = RecoverySession {
+        owner: owner.to_string(),
+        backup_id: setup.backup_id.clone(),
+        holders: setup.holders.clone(),
+        threshold: setup.threshold,
+        transaction_id: transactions::store(Transaction::new(TransactionType::Recovery, owner))?,
+        private_x,
+        shares: HashMap::new(),
+    };
+    Ok((session, packets))
+}
+
+/// Handle a recovery request on the holder side: it is stored as a transaction until the user
+/// approves it, ideally after checking with the owner out of band.
+///
+/// Returns the id of the transaction to give to [`approve_recovery`].
+pub fn receive_recovery_request(config: &Config, packet: &Packet) -> Result<String, RecoveryError> {
+    let Packet::RecoveryRequest(data) = packet else {
+        return Err(RecoveryError::WrongPacket);
+    };
+    verify_packet(config, packet).map_err(|_| RecoveryError::InvalidSignature)?;
+
+    let held = config.held_shares.get(&ed_key_id(&data.owner)).ok_or(RecoveryError::NoShare)?;
+    if held.backup_id != data.backup_id {
+        return Err(RecoveryError::NoShare);
+    }
+
+    Ok(transactions::store(Transaction::new(TransactionType::RecoveryApproval, &data.headers.author_key))?)
+}
+
+/// Send our share back to the user recovering their identity and close the transaction.
+/// `request` must be the one the transaction was created for.
+pub fn approve_recovery(config: &Config, request: &Packet, transaction_id: &str) -> Result<Packet, RecoveryError> {
+    let Packet::RecoveryRequest(data) = request else {
+        return Err(RecoveryError::WrongPacket);
+    };
+    verify_packet(config, request).map_err(|_| RecoveryError::InvalidSignature)?;
+
+    let transaction = transactions::load(transaction_id)?;
+    if *transaction.transaction_type() != TransactionType::RecoveryApproval || !same_ed_key(transaction.target_ed(), &data.headers.author_key) {
+        return Err(RecoveryError::TransactionMismatch);
+    }
+    let held = config.held_shares.get(&ed_key_id(&data.owner)).ok_or(RecoveryError::NoShare)?;
+    if held.backup_id != data.backup_id {
+        return Err(RecoveryError::NoShare);
+    }
+
+    let public_ed = fs::read_to_string(&config.me.public_ed_path)?;
+    let private_ed = fs::read_to_string(&config.me.private_ed_path)?;
+    let (holder_private_x, holder_x) = generate_x_keys();
+    let shared_key = generate_shared_key(&holder_private_x, &data.recovery_x).map_err(|_| RecoveryError::InvalidShare)?;
+
+    let mut packet = Packet::RecoveryResponse(RecoveryResponseData {
+        headers: PacketHeader {
+            action: String::from("recovery_response"),
+            author_key: public_ed,
+            signature: String::default(),
+            ..Default::default()
+        },
+        recipient: data.headers.author_key.clone(),
+        owner: data.owner.clone(),
+        backup_id: held.backup_id.clone(),
+        threshold: held.threshold,
+        holder_x,
+        share: encrypt_payload(&held.share, &shared_key)?,
+        backup: held.backup.clone(),
+    });
+    sign_packet(&mut packet, &private_ed)?;
+
+    transactions::delete(transaction_id)?;
+    Ok(packet)
+}
+
+/// Refuse a recovery request
+pub fn deny_recovery(transaction_id: &str) -> Result<(), RecoveryError> {
+    Ok(transactions::delete(transaction_id)?)
+}
+
+impl RecoverySession {
+    /// Add a share received from a holder, a new response of the same holder replaces their share.
+    ///
+    /// Returns the recovered private identity key (PKCS#8 PEM) once `threshold` shares are
+    /// collected. The key is only returned if it matches `owner`. Responses written by one of
+    /// `revoked_keys` are refused.
+    pub fn add_response(&mut self, packet: &Packet, revoked_keys: &[String]) -> Result<Option<String>, RecoveryError> {
+        let Packet::RecoveryResponse(data) = packet else {
+            return Err(RecoveryError::WrongPacket);
+        };
+        if !self.holders.iter().any(|holder| same_ed_key(holder, &data.headers.author_key)) {
+            return Err(RecoveryError::UnknownHolder);
+        }
+        verify_packet_signature(packet, revoked_keys).map_err(|_| RecoveryError::InvalidSignature)?;
+        if !same_ed_key(&data.owner, &self.owner) || data.backup_id != self.backup_id {
+            return Err(RecoveryError::NoShare);
+        }
+
+        let shared_key = generate_shared_key(&self.private_x, &data.holder_x).map_err(|_| RecoveryError::InvalidShare)?;
+        let share = decrypt_payload(&data.share, &shared_key).map_err(|_| RecoveryError::InvalidShare)?;
+        self.shares.insert(ed_key_id(&data.headers.author_key), share);
+
+        if self.shares.len() < self.threshold as usize {
+            return Ok(None);
+        }
+
+        let shares = self.shares.values()
+            .map(|share| URL_SAFE.decode(share).ok().and_then(|bytes| Share::try_from(bytes.as_slice()).ok()))
+            .collect::<Option<Vec<Share>>>()
+            .ok_or(RecoveryError::InvalidShare)?;
+        let backup_key = Sharks(self.threshold).recover(&shares).map_err(|_| RecoveryError::InvalidShare)?;
+        let private_ed = decrypt_payload(&data.backup, &URL_SAFE.encode(backup_key)).map_err(|_| RecoveryError::InvalidShare)?;
+
+        let identity = SigningKey::from_pkcs8_pem(&private_ed).map_err(|_| RecoveryError::InvalidShare)?;
+        if !same_ed_key(&encode_ed_public(&identity.verifying_key()), &self.owner) {
+            return Err(RecoveryError::InvalidShare);
+        }
+
+        transactions::delete(&self.transaction_id)?;
+        Ok(Some(private_ed))
+    }
+}
+
+impl From<std::io::Error> for RecoveryError {
+    fn from(err: std::io::Error) -> Self {
+        RecoveryError::Io(err)
+    }
+}
+
+impl From<StorageError> for RecoveryError {
+    fn from(err: StorageError) -> Self {
+        RecoveryError::Storage(err)
+    }
+}
+
+impl From<PacketGenerationError> for RecoveryError {
+    fn from(err: PacketGenerationError) -> Self {
+        RecoveryError::Generation(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{config::Config, packets::Packet, test::TestUser};
+
+    use super::{approve_recovery, create_recovery_shares, receive_recovery_request, request_recovery, store_recovery_share, RecoveryError};
+
+    /// Owner with a backup split between three holders, any two of them being enough
+    fn setup() -> (TestUser, Vec<TestUser>) {
+        let mut owner = TestUser::new("owner");
+        let mut holders = Vec::new();
+        for name in ["bob", "carol", "dave"] {
+            let mut holder = TestUser::new(name);
+            owner.befriend(&mut holder);
+            holders.push(holder);
+        }
+
+        let holder_keys: Vec<String> = holders.iter().map(|holder| holder.public_ed.clone()).collect();
+        let packets = create_recovery_shares(&mut owner.config, &holder_keys, 2).expect("Unable to create shares");
+        for (holder, packet) in holders.iter_mut().zip(packets) {
+            store_recovery_share(&mut holder.config, &packet).expect("Unable to store share");
+        }
+        (owner, holders)
+    }
+
+    fn respond(holder: &Config, request: &Packet) -> Packet {
+        let transaction_id = receive_recovery_request(holder, request).expect("Unable to receive request");
+        approve_recovery(holder, request, &transaction_id).expect("Unable to approve request")
+    }
+
+    #[test]
+    fn test_recovery_round_trip() {
+        let _guard = crate::test::config_folder();
+        let (owner, holders) = setup();
+        let recovery = owner.config.me.recovery.clone().expect("Backup not recorded");
+
+        let (mut session, requests) = request_recovery(&owner.public_ed, &recovery).expect("Unable to request recovery");
+        let first = respond(&holders[0].config, &requests[0]);
+        // shares of a holder whose key has been revoked are refused
+        let revoked = [first.headers().author_key.clone()];
+        assert!(matches!(session.add_response(&first, &revoked), Err(RecoveryError::InvalidSignature)));
+        assert!(matches!(session.add_response(&first, &[]), Ok(None)));
+        // a holder answering twice still counts once
+        assert!(matches!(session.add_response(&respond(&holders[0].config, &requests[0]), &[]), Ok(None)));
+
+        let Ok(Some(recovered)) = session.add_response(&respond(&holders[1].config, &requests[1]), &[]) else {
+            panic!("Unable to recover identity key");
+        };
+        assert_eq!(recovered, owner.private_ed);
+    }
+
+    #[test]
+    fn test_recovery_forged_shares() {
+        let _guard = crate::test::config_folder();
+        let (owner, mut holders) = setup();
+        let recovery = owner.config.me.recovery.clone().expect("Backup not recorded");
+
+        // the approval must answer the request the transaction was created for
+        let (_, requests) = request_recovery(&owner.public_ed, &recovery).expect("Unable to request recovery");
+        let (mut session, other_requests) = request_recovery(&owner.public_ed, &recovery).expect("Unable to request recovery");
+        let transaction_id = receive_recovery_request(&holders[0].config, &requests[0]).expect("Unable to receive request");
+        assert!(matches!(approve_recovery(&holders[0].config, &other_requests[0], &transaction_id), Err(RecoveryError::TransactionMismatch)));
+
+        // holders answering with the shares of another backup, enough to combine, can't make us
+        // recover another identity
+        let (_, other_holders) = setup();
+        for (holder, other) in holders.iter_mut().zip(&other_holders).take(2) {
+            let held = holder.config.held_shares.values_mut().next().expect("No share held");
+            let other = other.config.held_shares.values().next().expect("No share held");
+            held.share = other.share.clone();
+            held.backup = other.backup.clone();
+        }
+
+        assert!(matches!(session.add_response(&respond(&holders[0].config, &other_requests[0]), &[]), Ok(None)));
+        assert!(matches!(session.add_response(&respond(&holders[1].config, &other_requests[1]), &[]), Err(RecoveryError::InvalidShare)));
+        assert!(matches!(session.add_response(&respond(&holders[2].config, &other_requests[2]), &[]), Err(RecoveryError::InvalidShare)));
+    }
+}
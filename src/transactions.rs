@@ -1,10 +1,13 @@
 use std::{env, fmt::Display, fs};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum TransactionType {
-    FriendRequest
+    FriendRequest,
+    DeviceLink,
+    Recovery,
+    RecoveryApproval
 }
 
 impl Display for TransactionType {
@@ -13,17 +16,45 @@ impl Display for TransactionType {
             TransactionType::FriendRequest => {
                 write!(f, "friend_request")
             }
+            TransactionType::DeviceLink => {
+                write!(f, "device_link")
+            }
+            TransactionType::Recovery => {
+                write!(f, "recovery")
+            }
+            TransactionType::RecoveryApproval => {
+                write!(f, "recovery_approval")
+            }
         }
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Transaction {
     transaction_type: TransactionType,
     target_ed: String,
     status: bool
 }
 
+impl Transaction {
+    pub fn new(transaction_type: TransactionType, target_ed: &str) -> Self {
+        Self {
+            transaction_type,
+            target_ed: target_ed.to_string(),
+            status: false
+        }
+    }
+
+    pub fn transaction_type(&self) -> &TransactionType {
+        &self.transaction_type
+    }
+
+    /// Public ed25519 key of the user the transaction is about
+    pub fn target_ed(&self) -> &str {
+        &self.target_ed
+    }
+}
+
 #[derive(Debug)]
 pub enum StorageError {
     EnvVarNotSet(String),
@@ -60,6 +91,22 @@ pub fn store(transaction: Transaction) -> Result<String, StorageError> {
     Ok(transaction_id.to_string())
 }
 
+/// Read a pending transaction
+pub fn load(transaction_id: &str) -> Result<Transaction, StorageError> {
+    let config_path = env::var("PLUME_CONFIG")?;
+    let content = fs::read(format!("{config_path}/transactions/{transaction_id}"))?;
+
+    Ok(serde_json::from_slice(&content)?)
+}
+
+/// Delete a transaction once it has been answered
+pub fn delete(transaction_id: &str) -> Result<(), StorageError> {
+    let config_path = env::var("PLUME_CONFIG")?;
+    fs::remove_file(format!("{config_path}/transactions/{transaction_id}"))?;
+
+    Ok(())
+}
+
 // Storage eror handling, putting boilerplate code after useful onnes
 
 impl From<std::env::VarError> for StorageError {